@@ -1,7 +1,21 @@
 // State management
-let currentChatTitle = null;
+let currentChatId = null;
 let currentMessageParentId = null;
 let messageCache = new Map();
+let streamingContent = '';
+let branchChildren = new Map();
+let showArchived = false;
+let chatsById = new Map();
+let searchTimer = null;
+// Uploaded files waiting to go out with the next message
+let pendingAttachments = [];
+// Chats whose reply is being produced by a child actor: chat id -> poll state
+let pendingReplies = new Map();
+const REPLY_POLL_MS = 1000;
+const REPLY_POLL_ATTEMPTS = 180;
+// Identifies this browser to the actor's per-client rate limit
+const clientId = localStorage.getItem('clientId') || crypto.randomUUID();
+localStorage.setItem('clientId', clientId);
 let ws = null;
 let reconnectAttempts = 0;
 const MAX_RECONNECT_ATTEMPTS = 5;
@@ -81,7 +95,13 @@ function connectWebSocket() {
 
 function sendWebSocketMessage(message) {
     if (ws && ws.readyState === WebSocket.OPEN) {
-        ws.send(JSON.stringify(message));
+        // The actor has no clock of its own, so every command carries ours
+        ws.send(JSON.stringify({
+            ...message,
+            timestamp: Date.now(),
+            include_archived: showArchived,
+            client_id: clientId
+        }));
     } else {
         console.warn('WebSocket not connected');
         updateConnectionStatus('disconnected');
@@ -99,29 +119,142 @@ function handleWebSocketMessage(data) {
         
         // Update chat list if present
         if (data.chats) {
+            // Switch to a chat the server just created for us
+            if (data.type === 'chats_update' && data.chat_id) {
+                currentChatId = data.chat_id;
+            }
+
+            chatsById = new Map(data.chats.map(chat => [chat.id, chat]));
             renderChatList(data.chats);
             
             // Handle chat selection
-            if (currentChatTitle) {
-                const currentChat = data.chats.find(c => c.title === currentChatTitle);
-                if (currentChat) {
-                    selectChat(currentChat.title, currentChat.head);
-                }
+            const currentChat = data.chats.find(c => c.id === currentChatId);
+            if (currentChat) {
+                selectChat(currentChat.id, currentChat.head);
             } else if (data.chats.length > 0) {
-                selectChat(data.chats[0].title, data.chats[0].head);
+                selectChat(data.chats[0].id, data.chats[0].head);
             }
         }
 
+        // Handle streamed assistant deltas
+        if (data.type === 'message_delta' && data.chat_id === currentChatId) {
+            streamingContent += data.delta;
+            const messages = buildMessageChain(data.parent);
+            messages.push({ id: 'streaming', role: 'assistant', content: streamingContent });
+            renderMessages(messages);
+        }
+
         // Handle message updates
-        if (data.type === 'message_update' && data.chat_id === currentChatTitle) {
+        if (data.type === 'message_update' && data.chat_id === currentChatId) {
+            streamingContent = '';
             data.messages.forEach(msg => {
                 messageCache.set(msg.id, msg);
             });
-            if (currentChatTitle) {
-                const messages = buildMessageChain(data.messages[data.messages.length - 1].id);
-                renderMessages(messages);
+            if (currentChatId) {
+                currentMessageParentId = data.messages[data.messages.length - 1].id;
+                renderMessages(buildMessageChain(currentMessageParentId));
+                requestBranches();
+            }
+        }
+
+        // Handle a chat record changed by the server, such as a generated title
+        if (data.type === 'chat_updated') {
+            const item = document.querySelector(`[data-chat-id="${data.chat.id}"] span`);
+            if (item) {
+                item.textContent = data.chat.title;
             }
         }
+
+        // Warn when older messages were left out of the request
+        if (data.type === 'context_truncated' && data.chat_id === currentChatId) {
+            const { strategy, dropped } = data.truncation;
+            const notice = document.getElementById('chatNotice');
+            notice.textContent = strategy === 'summarize'
+                ? `${dropped} earlier messages were summarized to fit the model's context window.`
+                : `${dropped} earlier messages were left out to fit the model's context window.`;
+            notice.hidden = false;
+        }
+
+        // A child actor is producing the reply; poll the chat until it lands
+        if (data.type === 'message_queued') {
+            pollForReply(data.chat_id, data.head);
+        }
+
+        // Handle a chat's history, sent while polling for a queued reply
+        if (data.type === 'history') {
+            handleHistory(data.chat, data.messages);
+        }
+
+        // Handle search results for the sidebar
+        if (data.type === 'search_results') {
+            renderSearchResults(data.results);
+        }
+
+        // Handle branch structure for the current chat
+        if (data.type === 'branches' && data.chat_id === currentChatId) {
+            branchChildren = new Map(Object.entries(data.children));
+            // get_all only sends head chains, so cache the other branches too
+            data.messages.forEach(msg => {
+                messageCache.set(msg.id, msg);
+            });
+            renderMessages(buildMessageChain(currentMessageParentId));
+        }
+    } else if (data.status === 'error' && data.type === 'limit_exceeded') {
+        // A budget or rate limit stopped the request before it reached the provider
+        streamingContent = '';
+        const notice = document.getElementById('chatNotice');
+        notice.textContent = data.retry_after_ms
+            ? `${data.message}. Try again in ${Math.ceil(data.retry_after_ms / 1000)} seconds.`
+            : data.message;
+        notice.hidden = false;
+    }
+}
+
+function pollForReply(chatId, previousHead) {
+    clearTimeout(pendingReplies.get(chatId)?.timer);
+    pendingReplies.set(chatId, { previousHead, attempts: 0, timer: null });
+    requestHistory(chatId);
+}
+
+function requestHistory(chatId) {
+    const pending = pendingReplies.get(chatId);
+    if (!pending) return;
+    if (pending.attempts++ >= REPLY_POLL_ATTEMPTS) {
+        pendingReplies.delete(chatId);
+        const notice = document.getElementById('chatNotice');
+        notice.textContent = 'No reply arrived. Check the actor logs, or restart the chat actor.';
+        notice.hidden = false;
+        return;
+    }
+    pending.timer = setTimeout(() => {
+        sendWebSocketMessage({ type: 'get_history', chat_id: chatId });
+    }, REPLY_POLL_MS);
+}
+
+// The reply is in once the head has moved to an assistant message that asks for no tools
+function handleHistory(chat, messages) {
+    messages.forEach(msg => messageCache.set(msg.id, msg));
+    const item = document.querySelector(`[data-chat-id="${chat.id}"] span`);
+    if (item) {
+        item.textContent = chat.title;
+    }
+    if (chat.id === currentChatId) {
+        currentMessageParentId = chat.head;
+        renderMessages(buildMessageChain(chat.head));
+    }
+
+    const pending = pendingReplies.get(chat.id);
+    if (!pending) return;
+    const last = messages[messages.length - 1];
+    const done = chat.head !== pending.previousHead
+        && last && last.role === 'assistant' && !(last.tool_calls || []).length;
+    if (done) {
+        pendingReplies.delete(chat.id);
+        if (chat.id === currentChatId) {
+            requestBranches();
+        }
+    } else {
+        requestHistory(chat.id);
     }
 }
 
@@ -139,8 +272,8 @@ function closeNewChatModal() {
 // Chat creation
 async function submitNewChat() {
     const titleInput = document.getElementById('newChatTitle');
-    const title = titleInput.value.trim();
-    if (!title) return;
+    // Untitled chats are named after their first exchange
+    const title = titleInput.value.trim() || undefined;
 
     sendWebSocketMessage({
         type: 'new_chat',
@@ -150,12 +283,128 @@ async function submitNewChat() {
     closeNewChatModal();
 }
 
+function deleteChat(chatId) {
+    if (!confirm('Delete this chat? This cannot be undone.')) return;
+
+    if (chatId === currentChatId) {
+        currentChatId = null;
+        currentMessageParentId = null;
+    }
+
+    sendWebSocketMessage({
+        type: 'delete_chat',
+        chat_id: chatId
+    });
+}
+
+function pinChat(chatId, pinned) {
+    sendWebSocketMessage({
+        type: 'pin_chat',
+        chat_id: chatId,
+        pinned: pinned
+    });
+}
+
+function archiveChat(chatId, archived) {
+    if (archived && chatId === currentChatId && !showArchived) {
+        currentChatId = null;
+        currentMessageParentId = null;
+    }
+
+    sendWebSocketMessage({
+        type: 'archive_chat',
+        chat_id: chatId,
+        archived: archived
+    });
+}
+
+function toggleArchived(show) {
+    showArchived = show;
+    sendWebSocketMessage({
+        type: 'get_all'
+    });
+}
+
+// Upload a Claude.ai or ChatGPT conversations.json export
+async function importConversations(file) {
+    if (!file) return;
+
+    try {
+        const response = await fetch('/api/import', {
+            method: 'POST',
+            headers: { 'Content-Type': 'application/json' },
+            body: await file.text()
+        });
+        const result = await response.json();
+        if (result.status !== 'success') {
+            alert(`Import failed: ${result.message}`);
+            return;
+        }
+        alert(`Imported ${result.chats.length} chats (${result.skipped} skipped)`);
+        sendWebSocketMessage({
+            type: 'get_all'
+        });
+    } catch (error) {
+        console.error('Error importing conversations:', error);
+    }
+}
+
+// Debounce typing so each keystroke doesn't trigger a search
+function searchMessages(query) {
+    clearTimeout(searchTimer);
+    if (!query.trim()) {
+        renderSearchResults([]);
+        return;
+    }
+    searchTimer = setTimeout(() => {
+        sendWebSocketMessage({
+            type: 'search',
+            query: query
+        });
+    }, 250);
+}
+
+function renderSearchResults(results) {
+    const container = document.getElementById('searchResults');
+    container.innerHTML = results
+        .filter(result => result.chat_ids.some(id => chatsById.has(id)))
+        .map(result => {
+            const chatId = result.chat_ids.find(id => chatsById.has(id));
+            return `
+                <div class="search-result" onclick="openSearchResult('${chatId}')">
+                    <strong>${escapeHtml(chatsById.get(chatId).title)}</strong>
+                    <div>${highlightSnippet(result.snippet, result.highlights)}</div>
+                </div>
+            `;
+        }).join('');
+}
+
+// Highlight ranges are character offsets into the snippet
+function highlightSnippet(snippet, highlights) {
+    const chars = Array.from(snippet);
+    let html = '';
+    let position = 0;
+    highlights.forEach(({ start, end }) => {
+        html += escapeHtml(chars.slice(position, start).join(''));
+        html += `<mark>${escapeHtml(chars.slice(start, end).join(''))}</mark>`;
+        position = end;
+    });
+    return html + escapeHtml(chars.slice(position).join(''));
+}
+
+function openSearchResult(chatId) {
+    const chat = chatsById.get(chatId);
+    if (chat) {
+        selectChat(chat.id, chat.head);
+    }
+}
+
 // Message handling
 async function sendMessage() {
     const text = messageInput.value.trim();
     const sendButton = document.querySelector('.send-button');
 
-    if (!text || !currentChatTitle) return;
+    if ((!text && pendingAttachments.length === 0) || !currentChatId) return;
 
     try {
         messageInput.disabled = true;
@@ -164,9 +413,12 @@ async function sendMessage() {
         sendWebSocketMessage({
             type: 'send_message',
             content: text,
-            chat_id: currentChatTitle
+            chat_id: currentChatId,
+            attachments: pendingAttachments.map(({ id, name }) => ({ id, name }))
         });
 
+        pendingAttachments = [];
+        renderPendingAttachments();
         messageInput.value = '';
         messageInput.style.height = '2.5rem';
         messageInput.focus();
@@ -179,6 +431,101 @@ async function sendMessage() {
     }
 }
 
+// Files are uploaded as soon as they are picked and sent by id with the message
+async function uploadAttachments(files) {
+    if (!currentChatId) return;
+
+    for (const file of files) {
+        try {
+            const response = await fetch(
+                `/api/chats/${currentChatId}/attachments?name=${encodeURIComponent(file.name)}`,
+                {
+                    method: 'POST',
+                    headers: { 'Content-Type': file.type || 'application/octet-stream' },
+                    body: file
+                }
+            );
+            const result = await response.json();
+            if (result.status !== 'success') {
+                alert(`Could not attach ${file.name}: ${result.message}`);
+                continue;
+            }
+            pendingAttachments.push(result.attachment);
+        } catch (error) {
+            console.error('Error uploading attachment:', error);
+            alert(`Could not attach ${file.name}`);
+        }
+    }
+    renderPendingAttachments();
+}
+
+function removePendingAttachment(index) {
+    pendingAttachments.splice(index, 1);
+    renderPendingAttachments();
+}
+
+function renderPendingAttachments() {
+    const container = document.getElementById('pendingAttachments');
+    container.hidden = pendingAttachments.length === 0;
+    container.innerHTML = pendingAttachments.map((attachment, index) => `
+        <span class="attachment-chip">
+            ${escapeHtml(attachment.name || attachment.media_type)}
+            <button onclick="removePendingAttachment(${index})">&times;</button>
+        </span>
+    `).join('');
+}
+
+function regenerateMessage(messageId) {
+    if (!currentChatId) return;
+
+    sendWebSocketMessage({
+        type: 'regenerate',
+        chat_id: currentChatId,
+        message_id: messageId
+    });
+}
+
+function requestBranches() {
+    if (!currentChatId) return;
+
+    sendWebSocketMessage({
+        type: 'list_branches',
+        chat_id: currentChatId
+    });
+}
+
+// Switch to the branch containing a sibling message, following its latest replies
+function switchBranch(messageId) {
+    let headId = messageId;
+    while (branchChildren.has(headId)) {
+        const children = branchChildren.get(headId);
+        headId = children[children.length - 1];
+    }
+
+    sendWebSocketMessage({
+        type: 'set_head',
+        chat_id: currentChatId,
+        message_id: headId
+    });
+}
+
+function renderBranchPicker(msg) {
+    const siblings = msg.parent ? branchChildren.get(msg.parent) || [] : [];
+    if (siblings.length < 2) return '';
+
+    const index = siblings.indexOf(msg.id);
+    const previous = siblings[index - 1];
+    const next = siblings[index + 1];
+
+    return `
+        <div class="branch-picker">
+            <button class="message-action" ${previous ? `onclick="switchBranch('${previous}')"` : 'disabled'}>&lsaquo;</button>
+            <span>${index + 1} / ${siblings.length}</span>
+            <button class="message-action" ${next ? `onclick="switchBranch('${next}')"` : 'disabled'}>&rsaquo;</button>
+        </div>
+    `;
+}
+
 // Build the message chain from head to root
 function buildMessageChain(headId) {
     const messages = [];
@@ -195,16 +542,21 @@ function buildMessageChain(headId) {
 }
 
 // Chat selection and message rendering
-function selectChat(title, headId) {
-    currentChatTitle = title;
+function selectChat(chatId, headId) {
+    if (chatId !== currentChatId) {
+        branchChildren = new Map();
+        document.getElementById('chatNotice').hidden = true;
+    }
+    currentChatId = chatId;
     currentMessageParentId = headId;
+    requestBranches();
 
     const messages = buildMessageChain(headId);
     renderMessages(messages);
 
     // Update UI to show active chat
     document.querySelectorAll('.chat-item').forEach(chat => {
-        if (chat.textContent.trim() === title) {
+        if (chat.dataset.chatId === chatId) {
             chat.classList.add('active');
         } else {
             chat.classList.remove('active');
@@ -228,9 +580,18 @@ function renderChatList(chats) {
     }
 
     chatList.innerHTML = chats.map(chat => `
-        <div onclick="selectChat('${escapeHtml(chat.title)}', '${chat.head}')"
-             class="chat-item ${chat.title === currentChatTitle ? 'active' : ''}">
+        <div onclick="selectChat('${chat.id}', '${chat.head}')" data-chat-id="${chat.id}"
+             class="chat-item ${chat.id === currentChatId ? 'active' : ''} ${chat.archived ? 'archived' : ''}">
             <span>${escapeHtml(chat.title)}</span>
+            <span class="chat-actions">
+                <button class="chat-action ${chat.pinned ? 'pinned' : ''}"
+                        title="${chat.pinned ? 'Unpin chat' : 'Pin chat'}"
+                        onclick="event.stopPropagation(); pinChat('${chat.id}', ${!chat.pinned})">&#9733;</button>
+                <button class="chat-action" title="${chat.archived ? 'Unarchive chat' : 'Archive chat'}"
+                        onclick="event.stopPropagation(); archiveChat('${chat.id}', ${!chat.archived})">&#8615;</button>
+                <button class="chat-action" title="Delete chat"
+                        onclick="event.stopPropagation(); deleteChat('${chat.id}')">&times;</button>
+            </span>
         </div>
     `).join('');
 }
@@ -248,7 +609,14 @@ function renderMessages(messages) {
     messageArea.innerHTML = `<div class="message-container">${
         messages.map(msg => `
             <div class="message ${msg.role}" data-id="${msg.id}">
-                ${formatMessage(msg.content)}
+                ${renderBranchPicker(msg)}
+                ${msg.role === 'summary' ? '<div class="summary-label">Summary of earlier messages</div>' : ''}
+                ${(msg.attachments || []).map(renderAttachment).join('')}
+                ${msg.tool_result ? renderToolResult(msg) : formatMessage(msg.content)}
+                ${(msg.tool_calls || []).map(renderToolCall).join('')}
+                ${msg.role === 'assistant' && msg.id !== 'streaming' ? `
+                    <button class="message-action" onclick="regenerateMessage('${msg.id}')">Regenerate</button>
+                ` : ''}
             </div>
         `).join('')
     }</div>`;
@@ -256,6 +624,36 @@ function renderMessages(messages) {
     messageArea.scrollTop = messageArea.scrollHeight;
 }
 
+// Images are shown inline; documents as links to the stored file
+function renderAttachment(attachment) {
+    const url = `/api/attachments/${attachment.id}`;
+    const name = escapeHtml(attachment.name || attachment.media_type);
+    if (attachment.kind === 'image') {
+        return `<a href="${url}" target="_blank"><img class="message-image" src="${url}" alt="${name}"></a>`;
+    }
+    return `<a class="attachment-chip" href="${url}" target="_blank">${name}</a>`;
+}
+
+// Tool calls and their output are shown as compact blocks in the transcript
+function renderToolCall(call) {
+    return `
+        <div class="tool-call">
+            <span class="tool-label">Called ${escapeHtml(call.name)}</span>
+            <code>${escapeHtml(JSON.stringify(call.input))}</code>
+        </div>
+    `;
+}
+
+function renderToolResult(msg) {
+    const { name, is_error } = msg.tool_result;
+    return `
+        <details class="tool-output ${is_error ? 'error' : ''}">
+            <summary class="tool-label">${escapeHtml(name)} ${is_error ? 'failed' : 'result'}</summary>
+            <pre>${escapeHtml(msg.content)}</pre>
+        </details>
+    `;
+}
+
 // Message formatting
 function formatMessage(content) {
     // First escape HTML
@@ -0,0 +1,529 @@
+use crate::attachments::Attachment;
+use crate::bindings::ntwk::theater::http_types::{HttpRequest, HttpResponse};
+use crate::export::{export_chat, ExportFormat};
+use crate::import::import_conversations;
+use crate::limits::LimitExceeded;
+use crate::tools::{builtin_tools, ToolDefinition};
+use crate::usage::UsageQuery;
+use crate::{
+    error_response, json_response, prompt_value, regenerate, request_client, send_message,
+    set_provider, set_system_prompt, update_settings, Chat, ChatSettings, Message, State,
+    DEFAULT_TITLE, SEARCH_LIMIT,
+};
+use serde_json::{json, Value};
+
+// Every failure is reported as `{ "status": "error", "message": ... }` with a matching status code
+struct ApiError {
+    status: u16,
+    message: String,
+    // Extra fields merged into the error body
+    details: Option<Value>,
+}
+
+impl ApiError {
+    fn bad_request(message: impl Into<String>) -> Self {
+        ApiError {
+            status: 400,
+            message: message.into(),
+            details: None,
+        }
+    }
+
+    fn not_found(message: impl Into<String>) -> Self {
+        ApiError {
+            status: 404,
+            message: message.into(),
+            details: None,
+        }
+    }
+
+    // A budget or rate limit turned the request away before it reached the provider
+    fn limit_exceeded(e: LimitExceeded) -> Self {
+        ApiError {
+            status: 429,
+            message: e.message.clone(),
+            details: Some(e.to_json()),
+        }
+    }
+
+    // Failures while talking to the LLM provider
+    fn bad_gateway(message: impl Into<String>) -> Self {
+        ApiError {
+            status: 502,
+            message: message.into(),
+            details: None,
+        }
+    }
+}
+
+impl From<Box<dyn std::error::Error>> for ApiError {
+    fn from(e: Box<dyn std::error::Error>) -> Self {
+        ApiError {
+            status: 500,
+            message: e.to_string(),
+            details: None,
+        }
+    }
+}
+
+type ApiResult = Result<(u16, Value), ApiError>;
+
+// Handle a request under `/api`, with `segments` holding the path after it
+pub fn handle_api(state: &mut State, req: &HttpRequest, segments: &[&str]) -> HttpResponse {
+    // Exports and attachment downloads are documents rather than JSON
+    // envelopes, and uploads carry the raw file as their body
+    match (req.method.as_str(), segments) {
+        ("GET", ["chats", chat_id, "export"]) => {
+            return match export(state, req, chat_id) {
+                Ok(response) => response,
+                Err(e) => error_response(e.status, &e.message),
+            };
+        }
+        ("POST", ["chats", chat_id, "attachments"]) => {
+            return match upload_attachment(state, req, chat_id) {
+                Ok(attachment) => json_response(
+                    201,
+                    &json!({ "status": "success", "attachment": attachment }),
+                ),
+                Err(e) => error_response(e.status, &e.message),
+            };
+        }
+        ("GET", ["attachments", id]) => {
+            return match state.attachment_bytes(id) {
+                Ok((media_type, bytes)) => HttpResponse {
+                    status: 200,
+                    headers: vec![("Content-Type".to_string(), media_type)],
+                    body: Some(bytes),
+                },
+                Err(e) => error_response(404, &e.to_string()),
+            };
+        }
+        _ => {}
+    }
+
+    match route(state, req, segments) {
+        Ok((status, mut body)) => {
+            body["status"] = json!("success");
+            json_response(status, &body)
+        }
+        Err(ApiError {
+            status,
+            details: Some(mut body),
+            ..
+        }) => {
+            body["status"] = json!("error");
+            json_response(status, &body)
+        }
+        Err(e) => error_response(e.status, &e.message),
+    }
+}
+
+fn route(state: &mut State, req: &HttpRequest, segments: &[&str]) -> ApiResult {
+    let body = request_json(req)?;
+
+    match (req.method.as_str(), segments) {
+        ("GET", ["chats"]) => {
+            let include_archived = query_param(req, "archived") == Some("true");
+            Ok((200, json!({ "chats": state.list_chats(include_archived)? })))
+        }
+        ("POST", ["chats"]) => {
+            let title = optional_str(&body, "title")?
+                .filter(|title| !title.is_empty())
+                .unwrap_or(DEFAULT_TITLE);
+            let provider = optional_str(&body, "provider")?.map(String::from);
+            if let Some(name) = &provider {
+                state
+                    .provider_config(name)
+                    .map_err(|e| ApiError::bad_request(e.to_string()))?;
+            }
+            let chat = state.create_chat(title, provider)?;
+            Ok((201, json!({ "chat": chat })))
+        }
+        ("GET", ["chats", chat_id]) => {
+            let chat = find_chat(state, chat_id)?;
+            let messages = state.get_message_chain(chat.head.clone());
+            Ok((200, json!({ "chat": chat, "messages": messages })))
+        }
+        ("PATCH", ["chats", chat_id]) => {
+            find_chat(state, chat_id)?;
+            let title = required_str(&body, "title")?;
+            let chat = state.rename_chat(chat_id, title)?;
+            Ok((200, json!({ "chat": chat })))
+        }
+        ("PUT", ["chats", chat_id, "pinned"]) => {
+            find_chat(state, chat_id)?;
+            let pinned = required_bool(&body, "pinned")?;
+            let chat = state.pin_chat(chat_id, pinned)?;
+            Ok((200, json!({ "chat": chat })))
+        }
+        ("PUT", ["chats", chat_id, "archived"]) => {
+            find_chat(state, chat_id)?;
+            let archived = required_bool(&body, "archived")?;
+            let chat = state.archive_chat(chat_id, archived)?;
+            Ok((200, json!({ "chat": chat })))
+        }
+        ("DELETE", ["chats", chat_id]) => {
+            find_chat(state, chat_id)?;
+            let reclaimed = state.delete_chat(chat_id)?;
+            Ok((200, json!({ "chat_id": chat_id, "reclaimed": reclaimed })))
+        }
+        ("POST", ["chats", chat_id, "messages"]) => {
+            find_chat(state, chat_id)?;
+            let parent_id = optional_str(&body, "parent_id")?;
+            if let Some(parent_id) = parent_id {
+                find_chat_message(state, chat_id, parent_id)?;
+            }
+            let attachments = state
+                .resolve_attachments(&body["attachments"])
+                .map_err(|e| ApiError::bad_request(e.to_string()))?;
+            // A message may be attachments alone
+            let content = optional_str(&body, "content")?.unwrap_or_default();
+            if content.trim().is_empty() && attachments.is_empty() {
+                return Err(ApiError::bad_request("Missing field: content"));
+            }
+            let client = request_client(req, "http");
+            state
+                .check_limits(&client)
+                .map_err(ApiError::limit_exceeded)?;
+            if state.chat_actors.is_some() {
+                let command = json!({
+                    "type": "send_message",
+                    "chat_id": chat_id,
+                    "content": content,
+                    "parent_id": parent_id,
+                    "attachments": body["attachments"],
+                    "client_id": client,
+                    "timestamp": state.clock,
+                });
+                return queue_in_chat_actor(state, chat_id, &command);
+            }
+            let messages = send_message(
+                state,
+                chat_id,
+                content,
+                parent_id,
+                attachments,
+                &mut Vec::new(),
+            )
+            .map_err(|e| ApiError::bad_gateway(e.to_string()))?;
+            Ok((201, json!({ "messages": messages })))
+        }
+        ("POST", ["chats", chat_id, "regenerate"]) => {
+            find_chat(state, chat_id)?;
+            let message_id = required_str(&body, "message_id")?;
+            if find_chat_message(state, chat_id, message_id)?.role != "assistant" {
+                return Err(ApiError::bad_request(format!(
+                    "Message {} is not an assistant reply",
+                    message_id
+                )));
+            }
+            let client = request_client(req, "http");
+            state
+                .check_limits(&client)
+                .map_err(ApiError::limit_exceeded)?;
+            if state.chat_actors.is_some() {
+                let command = json!({
+                    "type": "regenerate",
+                    "chat_id": chat_id,
+                    "message_id": message_id,
+                    "client_id": client,
+                    "timestamp": state.clock,
+                });
+                return queue_in_chat_actor(state, chat_id, &command);
+            }
+            let messages = regenerate(state, chat_id, message_id, &mut Vec::new())
+                .map_err(|e| ApiError::bad_gateway(e.to_string()))?;
+            // Tool calls and their results come before the final reply
+            let message = messages.iter().rev().find(|m| m.role == "assistant");
+            Ok((201, json!({ "message": message, "messages": messages })))
+        }
+        ("POST", ["chats", chat_id, "actor", "restart"]) => {
+            find_chat(state, chat_id)?;
+            if state.chat_actors.is_none() {
+                return Err(ApiError::bad_request("Chat actors are not enabled"));
+            }
+            let actor_id = state.spawn_chat_actor(chat_id)?;
+            Ok((200, json!({ "chat_id": chat_id, "actor_id": actor_id })))
+        }
+        ("POST", ["chats", chat_id, "fork"]) => {
+            find_chat(state, chat_id)?;
+            let message_id = required_str(&body, "message_id")?;
+            find_message(state, message_id)?;
+            let title = required_str(&body, "title")?;
+            let chat = state.fork_chat(title, message_id, Some(chat_id))?;
+            Ok((201, json!({ "chat": chat })))
+        }
+        ("GET", ["chats", chat_id, "tree"]) => {
+            let chat = find_chat(state, chat_id)?;
+            let tree = state.get_chat_tree(&chat)?;
+            Ok((200, json!({ "head": chat.head, "tree": tree.nested() })))
+        }
+        ("GET", ["chats", chat_id, "branches"]) => {
+            let chat = find_chat(state, chat_id)?;
+            let tree = state.get_chat_tree(&chat)?;
+            Ok((
+                200,
+                json!({ "head": chat.head, "root": tree.root, "leaves": tree.leaves() }),
+            ))
+        }
+        ("PUT", ["chats", chat_id, "head"]) => {
+            find_chat(state, chat_id)?;
+            let message_id = required_str(&body, "message_id")?;
+            let chat = state
+                .set_head(chat_id, message_id)
+                .map_err(|e| ApiError::bad_request(e.to_string()))?;
+            Ok((200, json!({ "chat": chat })))
+        }
+        ("PUT", ["chats", chat_id, "provider"]) => {
+            find_chat(state, chat_id)?;
+            let provider = required_str(&body, "provider")?;
+            set_provider(state, chat_id, provider)
+                .map_err(|e| ApiError::bad_request(e.to_string()))?;
+            Ok((200, json!({ "chat": find_chat(state, chat_id)? })))
+        }
+        ("GET", ["chats", chat_id, "settings"]) => {
+            let chat = find_chat(state, chat_id)?;
+            Ok((200, json!({ "settings": chat.settings })))
+        }
+        ("PUT", ["chats", chat_id, "settings"]) => {
+            find_chat(state, chat_id)?;
+            let settings = serde_json::from_value::<ChatSettings>(body)
+                .map_err(|e| ApiError::bad_request(format!("Invalid settings: {}", e)))?;
+            state
+                .validate_tools(&settings)
+                .map_err(|e| ApiError::bad_request(e.to_string()))?;
+            let chat = update_settings(state, chat_id, settings)?;
+            Ok((200, json!({ "settings": chat.settings })))
+        }
+        ("GET", ["chats", chat_id, "system-prompt"]) => {
+            let chat = find_chat(state, chat_id)?;
+            Ok((
+                200,
+                json!({
+                    "system_prompt": chat.system_prompt,
+                    "effective": state.system_prompt(&chat),
+                }),
+            ))
+        }
+        ("PUT", ["chats", chat_id, "system-prompt"]) => {
+            find_chat(state, chat_id)?;
+            let prompt = prompt_value(&body["system_prompt"]);
+            let chat = set_system_prompt(state, chat_id, prompt)?;
+            Ok((200, json!({ "system_prompt": chat.system_prompt })))
+        }
+        ("GET", ["messages", message_id]) => {
+            Ok((200, json!({ "message": find_message(state, message_id)? })))
+        }
+        ("GET", ["messages", message_id, "children"]) => {
+            find_message(state, message_id)?;
+            Ok((200, json!({ "children": state.get_children(message_id)? })))
+        }
+        ("GET", ["tools"]) => {
+            let tools: Vec<ToolDefinition> = builtin_tools()
+                .into_iter()
+                .map(|tool| tool.definition)
+                .collect();
+            Ok((200, json!({ "tools": tools })))
+        }
+        ("GET", ["search"]) => {
+            let query = query_param(req, "q")
+                .map(decode_query)
+                .ok_or_else(|| ApiError::bad_request("Missing query parameter: q"))?;
+            let limit = match query_param(req, "limit") {
+                Some(limit) => limit
+                    .parse()
+                    .map_err(|_| ApiError::bad_request("limit must be a number"))?,
+                None => SEARCH_LIMIT,
+            };
+            let results = state.search(&query, limit)?;
+            Ok((200, json!({ "query": query, "results": results })))
+        }
+        ("POST", ["import"]) => {
+            let summary = import_conversations(state, &body)
+                .map_err(|e| ApiError::bad_request(e.to_string()))?;
+            Ok((201, json!(summary)))
+        }
+        ("POST", ["reindex"]) => {
+            let indexed = state.rebuild_search_index()?;
+            Ok((200, json!({ "indexed": indexed })))
+        }
+        ("GET", ["usage"]) => {
+            let query = UsageQuery {
+                from: query_param(req, "from"),
+                to: query_param(req, "to"),
+                chat_id: query_param(req, "chat_id"),
+            };
+            Ok((200, json!({ "usage": state.usage_report(&query)? })))
+        }
+        ("GET", ["system-prompt"]) => {
+            Ok((200, json!({ "system_prompt": state.default_system_prompt })))
+        }
+        ("PUT", ["system-prompt"]) => {
+            state.set_default_system_prompt(prompt_value(&body["system_prompt"]))?;
+            Ok((200, json!({ "system_prompt": state.default_system_prompt })))
+        }
+        _ => Err(ApiError::not_found(format!(
+            "No route for {} /api/{}",
+            req.method,
+            segments.join("/")
+        ))),
+    }
+}
+
+// Hand a command to the chat's child actor and answer at once with 202, so a
+// slow provider only holds up that chat. Clients poll `GET /api/chats/:id`
+// until the head moves past the one returned here.
+fn queue_in_chat_actor(state: &mut State, chat_id: &str, command: &Value) -> ApiResult {
+    let head = find_chat(state, chat_id)?.head;
+    state
+        .send_to_chat_actor(chat_id, command)
+        .map_err(|e| ApiError::bad_gateway(e.to_string()))?;
+    Ok((
+        202,
+        json!({ "chat_id": chat_id, "head": head, "queued": true }),
+    ))
+}
+
+// The body is the file itself, typed by its Content-Type header, with an
+// optional `name` query parameter
+fn upload_attachment(
+    state: &State,
+    req: &HttpRequest,
+    chat_id: &str,
+) -> Result<Attachment, ApiError> {
+    find_chat(state, chat_id)?;
+    let media_type = req
+        .headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-type"))
+        .map(|(_, value)| value.split(';').next().unwrap_or_default().trim())
+        .ok_or_else(|| ApiError::bad_request("Missing Content-Type header"))?;
+    let name = query_param(req, "name").map(decode_query);
+    state
+        .save_attachment(req.body.as_deref().unwrap_or_default(), media_type, name)
+        .map_err(|e| ApiError::bad_request(e.to_string()))
+}
+
+fn export(state: &State, req: &HttpRequest, chat_id: &str) -> Result<HttpResponse, ApiError> {
+    let chat = find_chat(state, chat_id)?;
+    let format = query_param(req, "format").unwrap_or("md");
+    let format = ExportFormat::parse(format).ok_or_else(|| {
+        ApiError::bad_request(format!(
+            "Unsupported export format: {} (expected md, json or html)",
+            format
+        ))
+    })?;
+    Ok(export_chat(state, &chat, format))
+}
+
+// An absent body is treated as an empty object; a malformed one is rejected
+fn request_json(req: &HttpRequest) -> Result<Value, ApiError> {
+    match req.body.as_deref() {
+        Some(body) if !body.is_empty() => serde_json::from_slice(body)
+            .map_err(|e| ApiError::bad_request(format!("Invalid JSON body: {}", e))),
+        _ => Ok(json!({})),
+    }
+}
+
+fn required_str<'a>(body: &'a Value, field: &str) -> Result<&'a str, ApiError> {
+    body[field]
+        .as_str()
+        .filter(|value| !value.is_empty())
+        .ok_or_else(|| ApiError::bad_request(format!("Missing field: {}", field)))
+}
+
+fn optional_str<'a>(body: &'a Value, field: &str) -> Result<Option<&'a str>, ApiError> {
+    match &body[field] {
+        Value::Null => Ok(None),
+        Value::String(value) => Ok(Some(value)),
+        _ => Err(ApiError::bad_request(format!(
+            "Field {} must be a string",
+            field
+        ))),
+    }
+}
+
+fn required_bool(body: &Value, field: &str) -> Result<bool, ApiError> {
+    body[field]
+        .as_bool()
+        .ok_or_else(|| ApiError::bad_request(format!("Field {} must be a boolean", field)))
+}
+
+fn query_param<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    let (_, query) = req.uri.split_once('?')?;
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+// Undo form encoding: `+` for spaces and `%XX` escapes
+fn decode_query(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let escaped = std::str::from_utf8(&bytes[i + 1..i + 3])
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match escaped {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn find_chat(state: &State, chat_id: &str) -> Result<Chat, ApiError> {
+    state
+        .load_chat(chat_id)
+        .map_err(|_| ApiError::not_found(format!("Chat not found: {}", chat_id)))
+}
+
+fn find_message(state: &State, message_id: &str) -> Result<Message, ApiError> {
+    state
+        .load_message(message_id)
+        .map_err(|_| ApiError::not_found(format!("Message not found: {}", message_id)))
+}
+
+// Messages from another chat's tree can't be used to move this chat's head
+fn find_chat_message(state: &State, chat_id: &str, message_id: &str) -> Result<Message, ApiError> {
+    let message = find_message(state, message_id)?;
+    if !state.chat_contains(chat_id, message_id)? {
+        return Err(ApiError::bad_request(format!(
+            "Message {} is not part of chat {}",
+            message_id, chat_id
+        )));
+    }
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_values_are_form_decoded() {
+        assert_eq!(decode_query("rust+wasm"), "rust wasm");
+        assert_eq!(decode_query("a%2Bb%3d%20c"), "a+b= c");
+        assert_eq!(decode_query("caf%C3%A9"), "café");
+    }
+
+    #[test]
+    fn malformed_escapes_are_kept() {
+        assert_eq!(decode_query("100%"), "100%");
+        assert_eq!(decode_query("%zz%4"), "%zz%4");
+        assert_eq!(decode_query("%FF"), "\u{FFFD}");
+    }
+}
@@ -0,0 +1,299 @@
+use crate::bindings::ntwk::theater::filesystem::{
+    create_dir, delete_file, path_exists, read_file, write_file,
+};
+use crate::bindings::ntwk::theater::runtime::log;
+use crate::{Message, State};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha1::{Digest, Sha1};
+use std::collections::HashSet;
+
+// Uploads past this size are refused; providers reject larger files anyway
+pub const ATTACHMENT_MAX_BYTES: usize = 10 * 1024 * 1024;
+// Rough token cost of one image, used when fitting the context window
+const IMAGE_TOKENS: u64 = 1600;
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AttachmentKind {
+    Image,
+    Document,
+}
+
+// A file attached to a message. The bytes live in the attachment store under
+// `id`, the sha1 of their content; messages only carry this reference.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Attachment {
+    pub id: String,
+    pub kind: AttachmentKind,
+    pub media_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub size: usize,
+    // Base64 content, filled in only while a request is being built
+    #[serde(skip)]
+    pub data: Option<String>,
+}
+
+impl Attachment {
+    pub fn estimated_tokens(&self) -> u64 {
+        match self.kind {
+            AttachmentKind::Image => IMAGE_TOKENS,
+            // PDFs are mostly markup and compression; text runs about four bytes a token
+            AttachmentKind::Document => self.size as u64 / 4,
+        }
+    }
+}
+
+// The stored form: metadata plus the base64 content, since the filesystem
+// host only writes text
+#[derive(Serialize, Deserialize)]
+struct StoredAttachment {
+    media_type: String,
+    size: usize,
+    data: String,
+}
+
+// The media types providers accept as image or document blocks
+pub fn attachment_kind(media_type: &str) -> Option<AttachmentKind> {
+    match media_type {
+        "image/jpeg" | "image/png" | "image/gif" | "image/webp" => Some(AttachmentKind::Image),
+        "application/pdf" | "text/plain" => Some(AttachmentKind::Document),
+        _ => None,
+    }
+}
+
+pub fn base64_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64_ALPHABET[((n >> (18 - 6 * i)) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+pub fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(text.len() / 4 * 3);
+    let mut n: u32 = 0;
+    let mut bits = 0;
+    for c in text
+        .bytes()
+        .filter(|c| !c.is_ascii_whitespace() && *c != b'=')
+    {
+        let value = BASE64_ALPHABET.iter().position(|&a| a == c)? as u32;
+        n = (n << 6) | value;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((n >> bits) as u8);
+            n &= (1 << bits) - 1;
+        }
+    }
+    Some(decoded)
+}
+
+impl State {
+    fn attachments_path(&self) -> String {
+        format!("{}/data/attachments", self.base_directory)
+    }
+
+    fn attachment_file(&self, id: &str) -> String {
+        format!("{}/{}.json", self.attachments_path(), id)
+    }
+
+    // Store an upload and return a reference for messages to carry. Identical
+    // bytes land in the same file.
+    pub fn save_attachment(
+        &self,
+        bytes: &[u8],
+        media_type: &str,
+        name: Option<String>,
+    ) -> Result<Attachment, Box<dyn std::error::Error>> {
+        let kind = attachment_kind(media_type)
+            .ok_or_else(|| format!("Unsupported attachment type: {}", media_type))?;
+        if bytes.is_empty() {
+            return Err("Attachment is empty".into());
+        }
+        if bytes.len() > ATTACHMENT_MAX_BYTES {
+            return Err(format!(
+                "Attachment is {} bytes; the limit is {}",
+                bytes.len(),
+                ATTACHMENT_MAX_BYTES
+            )
+            .into());
+        }
+
+        let id = format!("{:x}", Sha1::digest(bytes));
+        if !path_exists(&self.attachments_path())? {
+            create_dir(&self.attachments_path())?;
+        }
+        if !path_exists(&self.attachment_file(&id))? {
+            let stored = StoredAttachment {
+                media_type: media_type.to_string(),
+                size: bytes.len(),
+                data: base64_encode(bytes),
+            };
+            write_file(&self.attachment_file(&id), &serde_json::to_string(&stored)?)?;
+        }
+
+        Ok(Attachment {
+            id,
+            kind,
+            media_type: media_type.to_string(),
+            name,
+            size: bytes.len(),
+            data: None,
+        })
+    }
+
+    fn load_stored_attachment(
+        &self,
+        id: &str,
+    ) -> Result<StoredAttachment, Box<dyn std::error::Error>> {
+        if !id.chars().all(|c| c.is_ascii_hexdigit()) || !path_exists(&self.attachment_file(id))? {
+            return Err(format!("Attachment not found: {}", id).into());
+        }
+        Ok(serde_json::from_slice(&read_file(
+            &self.attachment_file(id),
+        )?)?)
+    }
+
+    // The reference for an uploaded attachment, for messages that cite it by id
+    pub fn load_attachment(&self, id: &str) -> Result<Attachment, Box<dyn std::error::Error>> {
+        let stored = self.load_stored_attachment(id)?;
+        let kind = attachment_kind(&stored.media_type)
+            .ok_or_else(|| format!("Unsupported attachment type: {}", stored.media_type))?;
+        Ok(Attachment {
+            id: id.to_string(),
+            kind,
+            media_type: stored.media_type,
+            name: None,
+            size: stored.size,
+            data: None,
+        })
+    }
+
+    // Look up the attachments a client listed for a new message, either as
+    // upload ids or as `{ "id", "name" }` objects
+    pub fn resolve_attachments(
+        &self,
+        refs: &Value,
+    ) -> Result<Vec<Attachment>, Box<dyn std::error::Error>> {
+        let Some(refs) = refs.as_array() else {
+            return Ok(Vec::new());
+        };
+        refs.iter()
+            .map(|reference| {
+                let id = reference
+                    .as_str()
+                    .or_else(|| reference["id"].as_str())
+                    .ok_or("Attachments must be ids or objects with an id")?;
+                let mut attachment = self.load_attachment(id)?;
+                attachment.name = reference["name"].as_str().map(String::from);
+                Ok(attachment)
+            })
+            .collect()
+    }
+
+    // The raw bytes and media type, for serving an attachment back to clients
+    pub fn attachment_bytes(
+        &self,
+        id: &str,
+    ) -> Result<(String, Vec<u8>), Box<dyn std::error::Error>> {
+        let stored = self.load_stored_attachment(id)?;
+        let bytes = base64_decode(&stored.data).ok_or("Attachment data is corrupt")?;
+        Ok((stored.media_type, bytes))
+    }
+
+    // Delete the files of attachments that swept messages used, unless a
+    // remaining message still refers to them. Only those are candidates, so an
+    // upload that hasn't been sent yet is left alone.
+    pub fn sweep_attachments(
+        &self,
+        mut candidates: HashSet<String>,
+        remaining: &HashSet<String>,
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        for id in remaining {
+            if candidates.is_empty() {
+                break;
+            }
+            match self.load_message(id) {
+                Ok(message) => {
+                    for attachment in &message.attachments {
+                        candidates.remove(&attachment.id);
+                    }
+                }
+                // Keep everything rather than risk deleting a file still in use
+                Err(e) => {
+                    log(&format!("Error loading message {}: {}", id, e));
+                    return Ok(0);
+                }
+            }
+        }
+
+        let mut removed = 0;
+        for id in candidates {
+            match delete_file(&self.attachment_file(&id)) {
+                Ok(()) => removed += 1,
+                Err(e) => log(&format!("Error deleting attachment {}: {}", id, e)),
+            }
+        }
+        log(&format!("Removed {} unused attachments", removed));
+        Ok(removed)
+    }
+
+    // Fill in the content of every attachment in the chain before it is sent.
+    // A missing file is logged and the attachment goes without content.
+    pub fn load_attachment_data(&self, messages: &mut [Message]) {
+        for attachment in messages.iter_mut().flat_map(|m| m.attachments.iter_mut()) {
+            match self.load_stored_attachment(&attachment.id) {
+                Ok(stored) => attachment.data = Some(stored.data),
+                Err(e) => log(&format!("Error loading attachment: {}", e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_padding() {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foo"), "Zm9v");
+        assert_eq!(base64_encode(&[0, 255, 254, 1]), "AP/+AQ==");
+    }
+
+    #[test]
+    fn base64_round_trip() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        for len in 0..bytes.len() {
+            let encoded = base64_encode(&bytes[..len]);
+            assert_eq!(base64_decode(&encoded).as_deref(), Some(&bytes[..len]));
+        }
+    }
+
+    #[test]
+    fn base64_decode_input() {
+        assert_eq!(base64_decode("Zm9v\nYmFy").as_deref(), Some(&b"foobar"[..]));
+        assert_eq!(base64_decode("Zg"), Some(b"f".to_vec()));
+        assert_eq!(base64_decode("Zm9v!"), None);
+    }
+}
@@ -0,0 +1,70 @@
+use crate::bindings::ntwk::theater::message_server_host::send;
+use crate::bindings::ntwk::theater::runtime::{log, spawn};
+use crate::State;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+// Runs each chat's completions in a child actor of its own, so a slow
+// provider call only holds up that chat. The children share the parent's
+// filesystem and answer the commands in `message_server`. Messages and
+// attachments are content addressed, and chat records, trees, search indexes
+// and usage are kept per chat, so no two children rewrite the same file.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChatActorConfig {
+    // Manifest the children are spawned from; it should leave out the HTTP and
+    // WebSocket server handlers, which only the parent serves
+    pub manifest: String,
+}
+
+impl State {
+    // The child serving a chat, spawned on first use
+    pub fn chat_actor(&mut self, chat_id: &str) -> Result<String, Box<dyn std::error::Error>> {
+        if let Some(actor_id) = self.chat_actor_ids.get(chat_id) {
+            return Ok(actor_id.clone());
+        }
+        self.spawn_chat_actor(chat_id)
+    }
+
+    // Replace a chat's child with a fresh one
+    pub fn spawn_chat_actor(
+        &mut self,
+        chat_id: &str,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        self.load_chat(chat_id)?;
+        let config = self
+            .chat_actors
+            .as_ref()
+            .ok_or("Chat actors are not enabled")?;
+        let manifest = config.manifest.clone();
+        self.forget_chat_actor(chat_id);
+        let actor_id = spawn(&manifest);
+        log(&format!("Spawned actor {} for chat {}", actor_id, chat_id));
+        self.chat_actor_ids
+            .insert(chat_id.to_string(), actor_id.clone());
+        Ok(actor_id)
+    }
+
+    // Stop routing a chat to its child. The runtime offers no way to stop an
+    // actor, so the child is only forgotten; one whose chat was deleted fails
+    // any command still queued in it.
+    pub fn forget_chat_actor(&mut self, chat_id: &str) {
+        if let Some(actor_id) = self.chat_actor_ids.remove(chat_id) {
+            log(&format!("Forgot actor {} for chat {}", actor_id, chat_id));
+        }
+    }
+
+    // Hand a command to the chat's child without waiting for it to finish.
+    // It is marked `queued` so the child runs it rather than queueing it again,
+    // and leaves out the rate limit the parent already counted it against.
+    pub fn send_to_chat_actor(
+        &mut self,
+        chat_id: &str,
+        command: &Value,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let actor_id = self.chat_actor(chat_id)?;
+        let mut command = command.clone();
+        command["queued"] = json!(true);
+        send(&actor_id, &serde_json::to_vec(&command)?)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,480 @@
+use crate::providers::DEFAULT_MAX_TOKENS;
+use crate::{Chat, ChatSettings, Message, State};
+use serde::{Deserialize, Serialize};
+
+// Used when a model isn't in the table below
+const DEFAULT_CONTEXT_WINDOW: u64 = 8192;
+// Role markers and separators the provider adds around each message
+const MESSAGE_OVERHEAD_TOKENS: u64 = 4;
+// Room left for the summary when older messages are folded into one
+const SUMMARY_MAX_TOKENS: u32 = 512;
+
+// How to fit a chain that exceeds the model's context window
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(tag = "strategy", rename_all = "snake_case")]
+pub enum Truncation {
+    // Drop messages from the start of the chain until the rest fits
+    #[default]
+    DropOldest,
+    // Keep the opening `first` messages and the latest `last`
+    KeepEnds {
+        first: usize,
+        last: usize,
+    },
+    // Replace the dropped messages with a summary in the system prompt
+    Summarize,
+}
+
+impl Truncation {
+    fn name(&self) -> &'static str {
+        match self {
+            Truncation::DropOldest => "drop_oldest",
+            Truncation::KeepEnds { .. } => "keep_ends",
+            Truncation::Summarize => "summarize",
+        }
+    }
+}
+
+// Opt-in summarization that folds older turns into a stored `summary` message
+// once the live part of the chain grows past `threshold_tokens`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RollingSummary {
+    pub threshold_tokens: u64,
+    // Recent messages left out of the summary and still sent verbatim
+    #[serde(default = "default_keep_last")]
+    pub keep_last: usize,
+}
+
+fn default_keep_last() -> usize {
+    4
+}
+
+// What `fit_context` left out, reported to clients as a warning
+#[derive(Serialize, Debug)]
+pub struct TruncationReport {
+    pub strategy: &'static str,
+    pub dropped: usize,
+    pub estimated_tokens: u64,
+    pub budget: u64,
+}
+
+// The messages and system prompt to send, after truncation
+pub struct Context {
+    pub messages: Vec<Message>,
+    pub system: Option<String>,
+    pub truncation: Option<TruncationReport>,
+    // What the summarize strategy left out, after the summary in effect if
+    // any: the input for a new stored summary
+    pub unsummarized: Vec<Message>,
+}
+
+pub fn context_window(model: &str) -> u64 {
+    let model = model.to_lowercase();
+    if model.starts_with("claude") {
+        200_000
+    } else if model.starts_with("gpt-4o")
+        || model.starts_with("gpt-4-turbo")
+        || model.starts_with("o1")
+    {
+        128_000
+    } else if model.starts_with("gpt-4") {
+        8192
+    } else if model.starts_with("gpt-3.5") {
+        16_385
+    } else if model.starts_with("llama3.1") || model.starts_with("llama3.2") {
+        128_000
+    } else if model.starts_with("mistral") || model.starts_with("mixtral") {
+        32_768
+    } else {
+        DEFAULT_CONTEXT_WINDOW
+    }
+}
+
+// Rough characters per token for each model family's tokenizer
+fn chars_per_token(model: &str) -> f64 {
+    let model = model.to_lowercase();
+    if model.starts_with("claude") {
+        3.5
+    } else if model.starts_with("gpt") || model.starts_with("o1") {
+        4.0
+    } else {
+        3.8
+    }
+}
+
+pub fn estimate_tokens(model: &str, text: &str) -> u64 {
+    (text.chars().count() as f64 / chars_per_token(model)).ceil() as u64
+}
+
+// Replies carry the provider's own count of their tokens, tool calls included;
+// everything else is estimated
+fn message_tokens(model: &str, message: &Message) -> u64 {
+    let content = match &message.usage {
+        Some(usage) if message.role == "assistant" && usage.output_tokens > 0 => {
+            usage.output_tokens
+        }
+        _ => {
+            estimate_tokens(model, &message.content)
+                + message
+                    .tool_calls
+                    .iter()
+                    .map(|call| estimate_tokens(model, &call.input.to_string()))
+                    .sum::<u64>()
+                + message
+                    .attachments
+                    .iter()
+                    .map(|attachment| attachment.estimated_tokens())
+                    .sum::<u64>()
+        }
+    };
+    content + MESSAGE_OVERHEAD_TOKENS
+}
+
+// The first index at or after `start` holding a user message; providers
+// expect the conversation to open with one. When a tool loop leaves none, the
+// cut falls after the tool results, or before the call they answer, so no
+// result is sent without its call.
+fn next_user_turn(messages: &[Message], start: usize) -> usize {
+    (start..messages.len())
+        .find(|&i| messages[i].role == "user")
+        .or_else(|| (start..messages.len()).find(|&i| messages[i].role != "tool"))
+        .or_else(|| (0..start).rev().find(|&i| messages[i].role != "tool"))
+        .unwrap_or(0)
+}
+
+// How many opening messages to keep out of the `first` asked for, shrunk so
+// the cut doesn't separate tool calls from their results
+fn opening_end(messages: &[Message], first: usize) -> usize {
+    let mut end = first.min(messages.len());
+    while end > 0
+        && end < messages.len()
+        && (messages[end].role == "tool" || !messages[end - 1].tool_calls.is_empty())
+    {
+        end -= 1;
+    }
+    end
+}
+
+fn with_summary(system: Option<String>, summary: &str) -> String {
+    match system {
+        Some(system) => format!(
+            "{}\n\nSummary of the earlier conversation:\n{}",
+            system, summary
+        ),
+        None => format!("Summary of the earlier conversation:\n{}", summary),
+    }
+}
+
+// Split a chain at its latest summary into that summary and the messages
+// still sent verbatim: those after the last one it covers, minus summaries
+fn live_messages(chain: Vec<Message>) -> (Option<Message>, Vec<Message>) {
+    let Some(position) = chain.iter().rposition(|m| m.role == "summary") else {
+        return (None, chain);
+    };
+    let summary = chain[position].clone();
+    let from = summary
+        .summarizes
+        .as_deref()
+        .and_then(|id| {
+            chain[..position]
+                .iter()
+                .position(|m| m.id.as_deref() == Some(id))
+        })
+        .map_or(position, |covered| covered + 1);
+    let live = chain
+        .into_iter()
+        .skip(from)
+        .filter(|m| m.role != "summary")
+        .collect();
+    (Some(summary), live)
+}
+
+impl State {
+    fn model_name(&self, chat: &Chat) -> String {
+        let provider = chat.provider.as_deref().unwrap_or(&self.default_provider);
+        chat.settings
+            .model
+            .clone()
+            .or_else(|| {
+                self.provider_config(provider)
+                    .ok()
+                    .map(|config| config.model().to_string())
+            })
+            .unwrap_or_default()
+    }
+
+    // Trim the chain to fit the model's window, leaving room for the system
+    // prompt and the reply
+    pub fn fit_context(
+        &self,
+        chat: &Chat,
+        chain: Vec<Message>,
+    ) -> Result<Context, Box<dyn std::error::Error>> {
+        let model = self.model_name(chat);
+        let (summary, chain) = live_messages(chain);
+        let system = self.system_prompt(chat).map(String::from);
+        let system = match &summary {
+            Some(summary) => Some(with_summary(system, &summary.content)),
+            None => system,
+        };
+        let settings = &chat.settings;
+
+        let window = settings
+            .context_window
+            .unwrap_or_else(|| context_window(&model));
+        let reserved = u64::from(settings.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS))
+            + system
+                .as_deref()
+                .map_or(0, |system| estimate_tokens(&model, system));
+        let budget = window.saturating_sub(reserved);
+
+        let tokens: Vec<u64> = chain.iter().map(|m| message_tokens(&model, m)).collect();
+        let estimated_tokens: u64 = tokens.iter().sum();
+        if estimated_tokens <= budget {
+            return Ok(Context {
+                messages: chain,
+                system,
+                truncation: None,
+                unsummarized: Vec::new(),
+            });
+        }
+
+        let strategy = settings.truncation.clone().unwrap_or_default();
+        let budget = match strategy {
+            Truncation::Summarize => budget.saturating_sub(u64::from(SUMMARY_MAX_TOKENS)),
+            _ => budget,
+        };
+
+        // Opening messages that are always kept
+        let kept = match strategy {
+            Truncation::KeepEnds { first, .. } => opening_end(&chain, first),
+            _ => 0,
+        };
+        let kept_tokens: u64 = tokens[..kept].iter().sum();
+
+        // Drop from just after the kept opening until the tail fits
+        let mut start = match strategy {
+            Truncation::KeepEnds { last, .. } => chain.len().saturating_sub(last).max(kept),
+            _ => kept,
+        };
+        while start + 1 < chain.len() && kept_tokens + tokens[start..].iter().sum::<u64>() > budget
+        {
+            start += 1;
+        }
+        let start = next_user_turn(&chain, start).max(kept);
+
+        let dropped: Vec<Message> = chain[kept..start].to_vec();
+        let mut messages: Vec<Message> = chain[..kept].to_vec();
+        messages.extend_from_slice(&chain[start..]);
+
+        let unsummarized = match strategy {
+            Truncation::Summarize if !dropped.is_empty() => {
+                summary.into_iter().chain(dropped.iter().cloned()).collect()
+            }
+            _ => Vec::new(),
+        };
+
+        Ok(Context {
+            messages,
+            system,
+            truncation: Some(TruncationReport {
+                strategy: strategy.name(),
+                dropped: dropped.len(),
+                estimated_tokens,
+                budget,
+            }),
+            unsummarized,
+        })
+    }
+
+    // Fold all but the latest turns before `parent` into a new summary message
+    // when the chat's rolling summary threshold is exceeded. The summary is
+    // stored as a child of `parent`, so the next message continues from it.
+    pub fn roll_summary(
+        &self,
+        chat: &Chat,
+        parent: Option<&str>,
+    ) -> Result<Option<Message>, Box<dyn std::error::Error>> {
+        let Some(rolling) = &chat.settings.rolling_summary else {
+            return Ok(None);
+        };
+        let model = self.model_name(chat);
+        let (summary, live) = live_messages(self.get_message_chain(parent.map(String::from)));
+
+        let tokens: u64 = summary
+            .iter()
+            .chain(&live)
+            .map(|m| message_tokens(&model, m))
+            .sum();
+        if tokens <= rolling.threshold_tokens {
+            return Ok(None);
+        }
+
+        // Keep the verbatim tail starting on a user turn
+        let split = next_user_turn(&live, live.len().saturating_sub(rolling.keep_last));
+        if split == 0 {
+            return Ok(None);
+        }
+
+        // The previous summary is folded in so each one covers the whole history
+        let mut folded: Vec<Message> = summary.into_iter().collect();
+        folded.extend_from_slice(&live[..split]);
+        self.store_summary(chat, parent, &folded).map(Some)
+    }
+
+    // Summarize `folded` into a `summary` message stored as a child of
+    // `parent`. It covers up to the last message folded in, so later requests
+    // continuing from it send only what came after.
+    pub fn store_summary(
+        &self,
+        chat: &Chat,
+        parent: Option<&str>,
+        folded: &[Message],
+    ) -> Result<Message, Box<dyn std::error::Error>> {
+        let mut message = Message {
+            role: "summary".to_string(),
+            content: self.summarize(chat, folded)?,
+            parent: parent.map(String::from),
+            summarizes: folded.last().and_then(|message| message.id.clone()),
+            ..Default::default()
+        };
+        message.id = Some(self.save_message(&chat.id, &message)?);
+        Ok(message)
+    }
+
+    // Condense messages into a short paragraph with the provider's cheap model
+    pub fn summarize(
+        &self,
+        chat: &Chat,
+        messages: &[Message],
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let name = chat.provider.as_deref().unwrap_or(&self.default_provider);
+        let config = self.provider_config(name)?;
+        let model = config.title_model().unwrap_or(config.model()).to_string();
+
+        // Keep the transcript within the summarizing model's own window
+        let limit = (context_window(&model).saturating_sub(u64::from(SUMMARY_MAX_TOKENS)) as f64
+            * chars_per_token(&model)) as usize;
+        let mut transcript = String::new();
+        for message in messages {
+            transcript.push_str(&format!("{}: {}\n\n", message.role, message.content));
+        }
+        let skip = transcript.chars().count().saturating_sub(limit);
+        let transcript: String = transcript.chars().skip(skip).collect();
+
+        let request = Message {
+            role: "user".to_string(),
+            content: format!(
+                "Summarize this conversation in one paragraph, keeping names, \
+                 decisions and open questions. Reply with the summary only.\n\n{}",
+                transcript
+            ),
+            ..Default::default()
+        };
+        let settings = ChatSettings {
+            model: Some(model),
+            max_tokens: Some(SUMMARY_MAX_TOKENS),
+            ..ChatSettings::default()
+        };
+        let completion =
+            self.complete_with(Some(&chat.id), name, &[request], &settings, None, &[])?;
+        Ok(completion.content().trim().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::ToolCall;
+    use serde_json::json;
+
+    fn message(id: &str, role: &str) -> Message {
+        Message {
+            role: role.to_string(),
+            content: format!("{} message", role),
+            id: Some(id.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn tool_use(id: &str) -> Message {
+        let mut message = message(id, "assistant");
+        message.tool_calls = vec![ToolCall {
+            id: format!("call-{}", id),
+            name: "read_file".to_string(),
+            input: json!({ "path": "notes.txt" }),
+        }];
+        message
+    }
+
+    fn ids(messages: &[Message]) -> Vec<&str> {
+        messages.iter().filter_map(|m| m.id.as_deref()).collect()
+    }
+
+    #[test]
+    fn cut_starts_on_a_user_turn() {
+        let chain = vec![
+            message("a", "user"),
+            message("b", "assistant"),
+            message("c", "user"),
+            message("d", "assistant"),
+        ];
+        assert_eq!(next_user_turn(&chain, 1), 2);
+        assert_eq!(next_user_turn(&chain, 2), 2);
+    }
+
+    #[test]
+    fn cut_in_a_tool_loop_skips_tool_results() {
+        // One long tool loop with no user turn after the cut
+        let chain = vec![
+            message("a", "user"),
+            tool_use("b"),
+            message("c", "tool"),
+            tool_use("d"),
+            message("e", "tool"),
+        ];
+        assert_eq!(next_user_turn(&chain, 2), 3);
+        // Only results left: start with the call they answer
+        assert_eq!(next_user_turn(&chain, 4), 3);
+    }
+
+    #[test]
+    fn kept_opening_keeps_tool_groups_whole() {
+        let chain = vec![
+            message("a", "user"),
+            tool_use("b"),
+            message("c", "tool"),
+            message("d", "tool"),
+            message("e", "assistant"),
+            message("f", "user"),
+        ];
+        assert_eq!(opening_end(&chain, 2), 1);
+        assert_eq!(opening_end(&chain, 3), 1);
+        assert_eq!(opening_end(&chain, 4), 4);
+        assert_eq!(opening_end(&chain, 10), 6);
+    }
+
+    #[test]
+    fn live_messages_follow_the_latest_summary() {
+        let mut summary = message("s", "summary");
+        summary.summarizes = Some("b".to_string());
+        let chain = vec![
+            message("a", "user"),
+            message("b", "assistant"),
+            message("c", "user"),
+            summary,
+            message("d", "user"),
+        ];
+        let (summary, live) = live_messages(chain);
+        assert_eq!(summary.and_then(|m| m.id).as_deref(), Some("s"));
+        assert_eq!(ids(&live), ["c", "d"]);
+    }
+
+    #[test]
+    fn live_messages_without_a_summary() {
+        let chain = vec![message("a", "user"), message("b", "assistant")];
+        let (summary, live) = live_messages(chain);
+        assert!(summary.is_none());
+        assert_eq!(ids(&live), ["a", "b"]);
+    }
+}
@@ -0,0 +1,141 @@
+// The host exposes no clock, so timestamps arrive as text from clients, export
+// files and upstream response headers. These helpers convert between those and
+// Unix milliseconds.
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+// Days since the epoch for a proleptic Gregorian date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = y.div_euclid(400);
+    let year_of_era = y - era * 400;
+    let day_of_year = (153 * m + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+// The inverse of `days_from_civil`
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let m = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * m + 2) / 5 + 1;
+    let month = if m < 10 { m + 3 } else { m - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+fn to_millis(days: i64, hour: i64, minute: i64, second: i64, millis: i64) -> Option<u64> {
+    let seconds = days * 86400 + hour * 3600 + minute * 60 + second;
+    u64::try_from(seconds * 1000 + millis).ok()
+}
+
+// Parse an RFC 3339 UTC timestamp such as `2024-03-01T12:34:56.789Z`.
+// Offsets other than UTC are ignored.
+pub fn parse_rfc3339(text: &str) -> Option<u64> {
+    let number = |range: std::ops::Range<usize>| text.get(range)?.parse::<i64>().ok();
+    let millis = text
+        .get(19..)
+        .and_then(|rest| rest.strip_prefix('.'))
+        .map(|fraction| {
+            let digits: String = fraction
+                .chars()
+                .take_while(char::is_ascii_digit)
+                .chain("000".chars())
+                .take(3)
+                .collect();
+            digits.parse::<i64>().unwrap_or(0)
+        })
+        .unwrap_or(0);
+
+    to_millis(
+        days_from_civil(number(0..4)?, number(5..7)?, number(8..10)?),
+        number(11..13)?,
+        number(14..16)?,
+        number(17..19)?,
+        millis,
+    )
+}
+
+// Parse an HTTP `Date` header such as `Tue, 15 Oct 2024 12:00:00 GMT`
+pub fn parse_http_date(text: &str) -> Option<u64> {
+    let mut parts = text.split_whitespace().skip(1);
+    let day = parts.next()?.parse::<i64>().ok()?;
+    let month_name = parts.next()?;
+    let month = MONTHS.iter().position(|m| *m == month_name)? as i64 + 1;
+    let year = parts.next()?.parse::<i64>().ok()?;
+    let mut time = parts.next()?.split(':').map(|n| n.parse::<i64>().ok());
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+    to_millis(days_from_civil(year, month, day), hour, minute, second, 0)
+}
+
+// The UTC calendar day of a timestamp, as `YYYY-MM-DD`
+pub fn format_day(millis: u64) -> String {
+    let (year, month, day) = civil_from_days((millis / 86_400_000) as i64);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn civil_days_round_trip() {
+        for days in [-719468, -1, 0, 1, 11016, 19782, 2932896] {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+
+    #[test]
+    fn leap_days() {
+        assert_eq!(civil_from_days(19782), (2024, 2, 29));
+        assert_eq!(civil_from_days(19783), (2024, 3, 1));
+        assert_eq!(
+            days_from_civil(2000, 3, 1) - days_from_civil(2000, 2, 28),
+            2
+        );
+        assert_eq!(
+            days_from_civil(1900, 3, 1) - days_from_civil(1900, 2, 28),
+            1
+        );
+        assert_eq!(format_day(1709164800000), "2024-02-29");
+        assert_eq!(format_day(951782400000), "2000-02-29");
+    }
+
+    #[test]
+    fn http_dates() {
+        assert_eq!(
+            parse_http_date("Tue, 15 Oct 2024 12:00:00 GMT"),
+            Some(1728993600000)
+        );
+        assert_eq!(
+            parse_http_date("Thu, 29 Feb 2024 00:00:00 GMT"),
+            Some(1709164800000)
+        );
+        assert_eq!(parse_http_date("Thu, 01 Jan 1970 00:00:00 GMT"), Some(0));
+        assert_eq!(parse_http_date("Tue, 15 Okt 2024 12:00:00 GMT"), None);
+        assert_eq!(parse_http_date("Tue, 15 Oct 2024"), None);
+    }
+
+    #[test]
+    fn rfc3339_timestamps() {
+        assert_eq!(parse_rfc3339("2024-03-01T12:34:56Z"), Some(1709296496000));
+        assert_eq!(
+            parse_rfc3339("2024-03-01T12:34:56.789Z"),
+            Some(1709296496789)
+        );
+        assert_eq!(parse_rfc3339("2024-02-29T00:00:00.5Z"), Some(1709164800500));
+        assert_eq!(parse_rfc3339("yesterday"), None);
+    }
+}
@@ -0,0 +1,174 @@
+use crate::bindings::ntwk::theater::filesystem::read_file;
+use crate::bindings::ntwk::theater::http_types::HttpResponse;
+use crate::bindings::ntwk::theater::runtime::log;
+use crate::{Chat, Message, State};
+use serde_json::json;
+
+// Undo the app layout's full-height flex body so the transcript scrolls as a page
+const EXPORT_STYLE_OVERRIDES: &str = "body { height: auto; display: block; padding: 2rem; }\n\
+     .message-container { max-width: 800px; margin: 0 auto; }\n\
+     h1 { max-width: 800px; margin: 0 auto 1.5rem; font-size: 1.5rem; }\n";
+
+pub enum ExportFormat {
+    Markdown,
+    Json,
+    Html,
+}
+
+impl ExportFormat {
+    pub fn parse(format: &str) -> Option<Self> {
+        match format {
+            "md" | "markdown" => Some(ExportFormat::Markdown),
+            "json" => Some(ExportFormat::Json),
+            "html" => Some(ExportFormat::Html),
+            _ => None,
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Markdown => "md",
+            ExportFormat::Json => "json",
+            ExportFormat::Html => "html",
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Markdown => "text/markdown; charset=utf-8",
+            ExportFormat::Json => "application/json",
+            ExportFormat::Html => "text/html; charset=utf-8",
+        }
+    }
+}
+
+// Render the chat's current chain, root first, as a downloadable document
+pub fn export_chat(state: &State, chat: &Chat, format: ExportFormat) -> HttpResponse {
+    let messages = state.get_message_chain(chat.head.clone());
+    let body = match format {
+        ExportFormat::Markdown => to_markdown(chat, &messages),
+        ExportFormat::Json => to_json(state, chat, &messages),
+        ExportFormat::Html => to_html(chat, &messages),
+    };
+
+    HttpResponse {
+        status: 200,
+        headers: vec![
+            (
+                "Content-Type".to_string(),
+                format.content_type().to_string(),
+            ),
+            (
+                "Content-Disposition".to_string(),
+                format!(
+                    "attachment; filename=\"{}.{}\"",
+                    file_stem(chat),
+                    format.extension()
+                ),
+            ),
+        ],
+        body: Some(body.into_bytes()),
+    }
+}
+
+fn role_label(role: &str) -> String {
+    let mut chars = role.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+// Attachments and tool calls have no text of their own, so they are spelled out
+// around the content
+fn message_text(message: &Message) -> String {
+    let mut text = String::new();
+    for attachment in &message.attachments {
+        text.push_str(&format!(
+            "Attached {} ({})\n\n",
+            attachment.name.as_deref().unwrap_or(&attachment.id),
+            attachment.media_type
+        ));
+    }
+    text.push_str(message.content.trim_end());
+    for call in &message.tool_calls {
+        if !text.is_empty() {
+            text.push_str("\n\n");
+        }
+        text.push_str(&format!("Called {} with {}", call.name, call.input));
+    }
+    text
+}
+
+fn to_markdown(chat: &Chat, messages: &[Message]) -> String {
+    let mut markdown = format!("# {}\n", chat.title);
+    for message in messages {
+        markdown.push_str(&format!(
+            "\n## {}\n\n{}\n",
+            role_label(&message.role),
+            message_text(message)
+        ));
+    }
+    markdown
+}
+
+fn to_json(state: &State, chat: &Chat, messages: &[Message]) -> String {
+    let document = json!({
+        "chat": chat,
+        "exported_at": state.clock,
+        "messages": messages,
+    });
+    serde_json::to_string_pretty(&document).unwrap()
+}
+
+// Inline the app stylesheet so the page renders the same when opened offline
+fn to_html(chat: &Chat, messages: &[Message]) -> String {
+    let styles = match read_file("styles.css") {
+        Ok(content) => String::from_utf8_lossy(&content).into_owned(),
+        Err(e) => {
+            log(&format!("Error reading styles.css for export: {}", e));
+            String::new()
+        }
+    };
+
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"UTF-8\">\n\
+         <title>{title}</title>\n<style>\n{styles}\n{overrides}</style>\n</head>\n<body>\n\
+         <h1>{title}</h1>\n<div class=\"message-container\">\n",
+        title = escape_html(&chat.title),
+        styles = styles,
+        overrides = EXPORT_STYLE_OVERRIDES,
+    );
+    for message in messages {
+        html.push_str(&format!(
+            "<div class=\"message {}\">{}</div>\n",
+            escape_html(&message.role),
+            escape_html(&message_text(message))
+        ));
+    }
+    html.push_str("</div>\n</body>\n</html>\n");
+    html
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// A filesystem-friendly name derived from the title, falling back to the id
+fn file_stem(chat: &Chat) -> String {
+    let slug = chat
+        .title
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+        .to_lowercase();
+    if slug.is_empty() {
+        chat.id.clone()
+    } else {
+        slug
+    }
+}
@@ -0,0 +1,388 @@
+use crate::bindings::ntwk::theater::runtime::log;
+use crate::dates::parse_rfc3339;
+use crate::{Chat, ChatSettings, Message, State, TreeIndex};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+
+#[derive(Serialize, Debug, Default)]
+pub struct ImportSummary {
+    pub chats: Vec<Chat>,
+    pub messages: usize,
+    // Conversations that were empty, unrecognized or already imported
+    pub skipped: usize,
+}
+
+// A conversation converted to our message format, parents before children
+struct Conversation {
+    title: String,
+    created_at: u64,
+    updated_at: u64,
+    messages: Vec<ImportedMessage>,
+    // Source id of the message the conversation was left on
+    current: Option<String>,
+}
+
+struct ImportedMessage {
+    source_id: String,
+    source_parent: Option<String>,
+    // None for entries with nothing to keep, such as hidden system nodes;
+    // their children are attached to the nearest kept ancestor
+    message: Option<(String, String)>,
+}
+
+// Accepts a bare export array, a single conversation or `{ "conversations": [...] }`
+pub fn import_conversations(
+    state: &mut State,
+    body: &Value,
+) -> Result<ImportSummary, Box<dyn std::error::Error>> {
+    let conversations = match body {
+        Value::Array(conversations) => conversations.clone(),
+        Value::Object(object) => match object.get("conversations") {
+            Some(Value::Array(conversations)) => conversations.clone(),
+            _ => vec![body.clone()],
+        },
+        _ => return Err("Expected a conversation export".into()),
+    };
+
+    let mut chats = state.get_all_chats()?;
+    let mut summary = ImportSummary::default();
+    for conversation in &conversations {
+        let parsed = if conversation.get("mapping").is_some() {
+            parse_chatgpt(conversation)
+        } else if conversation.get("chat_messages").is_some() {
+            parse_claude(conversation)
+        } else {
+            None
+        };
+        let Some(parsed) = parsed else {
+            summary.skipped += 1;
+            continue;
+        };
+
+        let (head, tree) = write_conversation(state, &parsed)?;
+        let Some(head) = head else {
+            summary.skipped += 1;
+            continue;
+        };
+
+        // Messages are content addressed, so a re-import lands on the same head
+        if chats
+            .iter()
+            .any(|chat| chat.title == parsed.title && chat.head.as_deref() == Some(head.as_str()))
+        {
+            summary.skipped += 1;
+            continue;
+        }
+
+        let chat = Chat {
+            id: state.new_chat_id(&parsed.title, &chats)?,
+            title: parsed.title,
+            head: Some(head),
+            provider: None,
+            settings: ChatSettings::default(),
+            system_prompt: None,
+            pinned: false,
+            archived: false,
+            created_at: parsed.created_at,
+            updated_at: parsed.updated_at,
+        };
+        state.clock = state.clock.max(chat.updated_at);
+        state.write_tree_index(&chat.id, &tree)?;
+        state.save_chat(&chat)?;
+        summary.messages += tree.ids().count();
+        summary.chats.push(chat.clone());
+        chats.push(chat);
+    }
+
+    state.rebuild_search_index()?;
+    log(&format!(
+        "Imported {} chats with {} messages, skipped {}",
+        summary.chats.len(),
+        summary.messages,
+        summary.skipped
+    ));
+    Ok(summary)
+}
+
+// Store the messages and return the head for the conversation's current
+// message, along with the tree of every message written
+fn write_conversation(
+    state: &State,
+    conversation: &Conversation,
+) -> Result<(Option<String>, TreeIndex), Box<dyn std::error::Error>> {
+    // Source id -> id of the nearest stored message at or above it
+    let mut stored: HashMap<&str, Option<String>> = HashMap::new();
+    let mut last = None;
+    let mut tree = TreeIndex::default();
+
+    for entry in &conversation.messages {
+        let parent = entry
+            .source_parent
+            .as_deref()
+            .and_then(|source| stored.get(source).cloned().flatten());
+        let id = match &entry.message {
+            Some((role, content)) => {
+                let id = state.write_message(&Message {
+                    role: role.clone(),
+                    content: content.clone(),
+                    parent: parent.clone(),
+                    ..Default::default()
+                })?;
+                tree.insert(&id, parent.as_deref());
+                last = Some(id.clone());
+                Some(id)
+            }
+            None => parent,
+        };
+        stored.insert(&entry.source_id, id);
+    }
+
+    let head = conversation
+        .current
+        .as_deref()
+        .and_then(|current| stored.get(current).cloned().flatten())
+        .or(last);
+    Ok((head, tree))
+}
+
+// ChatGPT exports keep every branch in `mapping`, a tree of nodes keyed by id
+fn parse_chatgpt(conversation: &Value) -> Option<Conversation> {
+    let mapping = conversation["mapping"].as_object()?;
+
+    // Walk down from the roots so every parent is written before its children
+    let mut pending: Vec<&String> = mapping
+        .iter()
+        .filter(|(_, node)| {
+            node["parent"]
+                .as_str()
+                .is_none_or(|parent| !mapping.contains_key(parent))
+        })
+        .map(|(id, _)| id)
+        .collect();
+    let mut messages = Vec::new();
+    while let Some(id) = pending.pop() {
+        let node = &mapping[id];
+        messages.push(ImportedMessage {
+            source_id: id.clone(),
+            source_parent: node["parent"].as_str().map(String::from),
+            message: chatgpt_message(&node["message"]),
+        });
+        for child in node["children"].as_array().into_iter().flatten().rev() {
+            if let Some(child) = child.as_str().and_then(|c| mapping.get_key_value(c)) {
+                pending.push(child.0);
+            }
+        }
+    }
+
+    Some(Conversation {
+        title: title(&conversation["title"]),
+        created_at: seconds_to_millis(&conversation["create_time"]),
+        updated_at: seconds_to_millis(&conversation["update_time"]),
+        messages,
+        current: conversation["current_node"].as_str().map(String::from),
+    })
+}
+
+fn chatgpt_message(message: &Value) -> Option<(String, String)> {
+    let role = match message["author"]["role"].as_str()? {
+        "user" => "user",
+        "assistant" => "assistant",
+        _ => return None,
+    };
+    let content = &message["content"];
+    let text = match content["parts"].as_array() {
+        // Non-text parts such as image pointers are dropped
+        Some(parts) => parts
+            .iter()
+            .filter_map(Value::as_str)
+            .collect::<Vec<_>>()
+            .join("\n"),
+        None => content["text"].as_str().unwrap_or_default().to_string(),
+    };
+    (!text.trim().is_empty()).then(|| (role.to_string(), text))
+}
+
+// Claude.ai exports list messages in order; newer ones also link parents
+fn parse_claude(conversation: &Value) -> Option<Conversation> {
+    let mut messages = Vec::new();
+    let mut previous: Option<String> = None;
+    for (index, message) in conversation["chat_messages"].as_array()?.iter().enumerate() {
+        let source_id = message["uuid"]
+            .as_str()
+            .map(String::from)
+            .unwrap_or_else(|| index.to_string());
+        let source_parent = message["parent_message_uuid"]
+            .as_str()
+            .map(String::from)
+            .or_else(|| previous.clone());
+        messages.push(ImportedMessage {
+            source_id: source_id.clone(),
+            source_parent,
+            message: claude_message(message),
+        });
+        previous = Some(source_id);
+    }
+
+    Some(Conversation {
+        title: title(&conversation["name"]),
+        created_at: parse_timestamp(&conversation["created_at"]),
+        updated_at: parse_timestamp(&conversation["updated_at"]),
+        messages,
+        current: conversation["current_leaf_message_uuid"]
+            .as_str()
+            .map(String::from),
+    })
+}
+
+fn claude_message(message: &Value) -> Option<(String, String)> {
+    let role = match message["sender"].as_str()? {
+        "human" => "user",
+        "assistant" => "assistant",
+        _ => return None,
+    };
+    let text = match message["content"].as_array() {
+        Some(blocks) => blocks
+            .iter()
+            .filter(|block| block["type"] == "text")
+            .filter_map(|block| block["text"].as_str())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        None => String::new(),
+    };
+    let text = if text.trim().is_empty() {
+        message["text"].as_str().unwrap_or_default().to_string()
+    } else {
+        text
+    };
+    (!text.trim().is_empty()).then(|| (role.to_string(), text))
+}
+
+fn title(value: &Value) -> String {
+    value
+        .as_str()
+        .map(str::trim)
+        .filter(|title| !title.is_empty())
+        .unwrap_or("Imported chat")
+        .to_string()
+}
+
+fn seconds_to_millis(value: &Value) -> u64 {
+    value
+        .as_f64()
+        .map_or(0, |seconds| (seconds * 1000.0) as u64)
+}
+
+fn parse_timestamp(value: &Value) -> u64 {
+    value.as_str().and_then(parse_rfc3339).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn summary(conversation: &Conversation) -> Vec<(&str, Option<&str>, Option<&str>)> {
+        conversation
+            .messages
+            .iter()
+            .map(|entry| {
+                (
+                    entry.source_id.as_str(),
+                    entry.source_parent.as_deref(),
+                    entry.message.as_ref().map(|(_, text)| text.as_str()),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn chatgpt_mappings_are_walked_parents_first() {
+        let conversation = parse_chatgpt(&json!({
+            "title": " Trip ",
+            "create_time": 1700000000.5,
+            "update_time": 1700000100.0,
+            "current_node": "b2",
+            "mapping": {
+                "b2": {
+                    "parent": "a",
+                    "children": [],
+                    "message": { "author": { "role": "assistant" }, "content": { "parts": ["Lisbon"] } }
+                },
+                "root": { "parent": null, "children": ["sys"], "message": null },
+                "a": {
+                    "parent": "sys",
+                    "children": ["b1", "b2"],
+                    "message": { "author": { "role": "user" }, "content": { "parts": ["Where", { "asset": 1 }, "to?"] } }
+                },
+                "sys": {
+                    "parent": "root",
+                    "children": ["a"],
+                    "message": { "author": { "role": "system" }, "content": { "parts": [""] } }
+                },
+                "b1": {
+                    "parent": "a",
+                    "children": [],
+                    "message": { "author": { "role": "assistant" }, "content": { "text": "Porto" } }
+                }
+            }
+        }))
+        .unwrap();
+
+        assert_eq!(conversation.title, "Trip");
+        assert_eq!(conversation.created_at, 1700000000500);
+        assert_eq!(conversation.updated_at, 1700000100000);
+        assert_eq!(conversation.current.as_deref(), Some("b2"));
+        assert_eq!(
+            summary(&conversation),
+            [
+                ("root", None, None),
+                ("sys", Some("root"), None),
+                ("a", Some("sys"), Some("Where\nto?")),
+                ("b1", Some("a"), Some("Porto")),
+                ("b2", Some("a"), Some("Lisbon")),
+            ]
+        );
+    }
+
+    #[test]
+    fn claude_messages_chain_unless_they_name_a_parent() {
+        let conversation = parse_claude(&json!({
+            "name": "",
+            "created_at": "2024-03-01T12:00:00Z",
+            "chat_messages": [
+                { "uuid": "q", "sender": "human", "text": "Hi" },
+                {
+                    "uuid": "r1",
+                    "sender": "assistant",
+                    "content": [{ "type": "text", "text": "Hello" }, { "type": "tool_use" }],
+                    "text": "ignored"
+                },
+                { "sender": "assistant", "content": [], "text": "Hey" },
+                { "uuid": "r3", "sender": "assistant", "parent_message_uuid": "q", "text": " " }
+            ]
+        }))
+        .unwrap();
+
+        assert_eq!(conversation.title, "Imported chat");
+        assert_eq!(conversation.created_at, 1709294400000);
+        assert_eq!(conversation.updated_at, 0);
+        assert_eq!(conversation.current, None);
+        assert_eq!(
+            summary(&conversation),
+            [
+                ("q", None, Some("Hi")),
+                ("r1", Some("q"), Some("Hello")),
+                ("2", Some("r1"), Some("Hey")),
+                ("r3", Some("q"), None),
+            ]
+        );
+        assert_eq!(conversation.messages[0].message.as_ref().unwrap().0, "user");
+    }
+
+    #[test]
+    fn exports_without_messages_are_not_conversations() {
+        assert!(parse_chatgpt(&json!({ "mapping": [] })).is_none());
+        assert!(parse_claude(&json!({ "chat_messages": {} })).is_none());
+    }
+}
@@ -1,49 +1,1025 @@
-// [Previous imports remain the same...]
+mod api;
+mod attachments;
+mod bindings;
+mod chat_actors;
+mod context;
+mod dates;
+mod export;
+mod import;
+mod limits;
+mod message_server;
+mod openai;
+mod providers;
+mod search;
+mod tools;
+mod usage;
+
+use attachments::Attachment;
+use bindings::exports::ntwk::theater::actor::Guest as ActorGuest;
+use bindings::exports::ntwk::theater::http_server::Guest as HttpGuest;
+use bindings::exports::ntwk::theater::message_server_client::Guest as MessageServerClientGuest;
+use bindings::exports::ntwk::theater::websocket_server::Guest as WebSocketGuest;
+use bindings::exports::ntwk::theater::websocket_server::{
+    MessageType, WebsocketMessage, WebsocketResponse,
+};
+use bindings::ntwk::theater::filesystem::{
+    create_dir, delete_file, list_files, path_exists, read_file, write_file,
+};
+use bindings::ntwk::theater::http_client::send_http;
+use bindings::ntwk::theater::http_types::{HttpRequest, HttpResponse};
+use bindings::ntwk::theater::runtime::log;
+use chat_actors::ChatActorConfig;
+use context::{Context, RollingSummary, Truncation};
+use dates::parse_http_date;
+use limits::{LimitExceeded, Limits};
+use providers::{AnthropicProvider, Completion, ProviderConfig, Usage};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha1::{Digest, Sha1};
+use std::collections::{HashMap, HashSet};
+use tools::{builtin_tools, ActorTool, ToolCall, ToolDefinition, ToolResult, TOOL_ROOT};
+use usage::{default_rates, Rate, UsageQuery};
+
+const MODEL: &str = "claude-3-5-sonnet-20241022";
+const TITLE_MODEL: &str = "claude-3-5-haiku-20241022";
+const DEFAULT_PROVIDER: &str = "anthropic";
+const SYSTEM_PROMPT_PATH: &str = "system-prompt.txt";
+const RATES_PATH: &str = "rates.json";
+const LIMITS_PATH: &str = "limits.json";
+const CHAT_ACTORS_PATH: &str = "chat-actors.json";
+const SEARCH_LIMIT: usize = 50;
+// Chats created without a title get this one, and only they are titled automatically
+const DEFAULT_TITLE: &str = "New Chat";
+const TITLE_MAX_TOKENS: u32 = 20;
+const TITLE_MAX_CHARS: usize = 60;
+// How much of each opening message is shown to the titling call
+const TITLE_EXCERPT_CHARS: usize = 1000;
+// Provider calls one reply may make while the model keeps asking for tools
+const MAX_TOOL_ROUNDS: usize = 8;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct Message {
+    role: String,
+    content: String,
+    parent: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    // Token counts reported by the provider for the reply that produced this message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    usage: Option<Usage>,
+    // For `summary` messages, the newest message folded into the summary
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    summarizes: Option<String>,
+    // Tools an assistant reply asked to run; their results follow as `tool` messages
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<ToolCall>,
+    // For `tool` messages, the call this output answers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tool_result: Option<ToolResult>,
+    // Images and documents sent with a user message, ahead of its text
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    attachments: Vec<Attachment>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Chat {
+    id: String,
+    title: String,
+    head: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    provider: Option<String>,
+    #[serde(default)]
+    settings: ChatSettings,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    system_prompt: Option<String>,
+    #[serde(default)]
+    pinned: bool,
+    #[serde(default)]
+    archived: bool,
+    // Unix milliseconds, taken from the request that created or last advanced the chat
+    #[serde(default)]
+    created_at: u64,
+    #[serde(default)]
+    updated_at: u64,
+}
+
+// Generation parameters for a chat; unset fields fall back to the provider defaults
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct ChatSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    top_p: Option<f64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    stop_sequences: Vec<String>,
+    // Overrides the model's known context window
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    context_window: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    truncation: Option<Truncation>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rolling_summary: Option<RollingSummary>,
+    // Names of the built-in tools the model may call in this chat
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<String>,
+    // Further tools served by other actors
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    actor_tools: Vec<ActorTool>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct State {
+    chat_directory: String,
+    base_directory: String,
+    providers: HashMap<String, ProviderConfig>,
+    default_provider: String,
+    default_system_prompt: Option<String>,
+    // Timestamp of the request being handled. The host has no clock, so this is
+    // whatever the client sent, kept monotonic by stepping past the last value.
+    #[serde(default)]
+    clock: u64,
+    // Latest Date header seen from a provider. Limits only trust this, since
+    // clients can send any timestamp they like.
+    #[serde(default)]
+    provider_time: u64,
+    // Prices per million tokens, keyed by model name or prefix
+    #[serde(default)]
+    rates: HashMap<String, Rate>,
+    #[serde(default)]
+    limits: Limits,
+    // Recent completion requests per client, for the rate limit
+    #[serde(default)]
+    request_log: HashMap<String, Vec<u64>>,
+    // Set when completions run in per-chat child actors
+    #[serde(default)]
+    chat_actors: Option<ChatActorConfig>,
+    // Chat id -> id of the child actor serving it
+    #[serde(default)]
+    chat_actor_ids: HashMap<String, String>,
+}
+
+// Maps each message id to the ids of the messages replying to it
+type ChildrenIndex = HashMap<String, Vec<String>>;
+
+// The messages one chat has created or reached. Ids are content addressed, so
+// two chats can hold the same message; each keeps its own links to it and
+// never sees replies that only the other chat made.
+#[derive(Serialize, Deserialize, Default, Debug)]
+struct TreeIndex {
+    // Messages whose parent is not in the chat
+    roots: Vec<String>,
+    children: ChildrenIndex,
+}
+
+impl TreeIndex {
+    fn contains(&self, id: &str) -> bool {
+        self.roots.iter().any(|root| root == id)
+            || self.children.values().flatten().any(|child| child == id)
+    }
+
+    fn ids(&self) -> impl Iterator<Item = &String> {
+        self.roots.iter().chain(self.children.values().flatten())
+    }
+
+    // Link a message under its parent, or as a root when the parent is not in
+    // the chat. Returns whether the index changed.
+    fn insert(&mut self, id: &str, parent: Option<&str>) -> bool {
+        if self.contains(id) {
+            return false;
+        }
+        match parent.filter(|parent| self.contains(parent)) {
+            Some(parent) => self
+                .children
+                .entry(parent.to_string())
+                .or_default()
+                .push(id.to_string()),
+            None => self.roots.push(id.to_string()),
+        }
+        true
+    }
+}
+
+// The messages reachable from the root of a chat's current chain
+struct ChatTree {
+    root: Option<String>,
+    children: ChildrenIndex,
+    messages: Vec<Message>,
+}
+
+impl ChatTree {
+    fn leaves(&self) -> Vec<&String> {
+        self.messages
+            .iter()
+            .filter_map(|m| m.id.as_ref())
+            .filter(|id| !self.children.contains_key(*id))
+            .collect()
+    }
+
+    // Nest each message under its parent, starting from the root
+    fn nested(&self) -> Option<Value> {
+        let messages: HashMap<&str, &Message> = self
+            .messages
+            .iter()
+            .filter_map(|m| Some((m.id.as_deref()?, m)))
+            .collect();
+        self.root
+            .as_deref()
+            .map(|root| self.nested_node(root, &messages))
+    }
+
+    fn nested_node(&self, id: &str, messages: &HashMap<&str, &Message>) -> Value {
+        let mut node = json!(messages.get(id));
+        node["children"] = self
+            .children
+            .get(id)
+            .into_iter()
+            .flatten()
+            .map(|child| self.nested_node(child, messages))
+            .collect();
+        node
+    }
+}
+
+struct Component;
 
 impl State {
-    // [Previous method implementations remain the same...]
+    fn chat_path(&self) -> String {
+        format!("{}/data/{}", self.base_directory, self.chat_directory)
+    }
+
+    // Store a message and add it to the indexes of the chat it was written for.
+    // The tree learns the id before the file exists, so a collection running
+    // in another actor never finds the file without a tree holding it.
+    fn save_message(
+        &self,
+        chat_id: &str,
+        message: &Message,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let id = message_id(message)?;
+        self.index_tree_message(chat_id, &id, message.parent.as_deref())?;
+        self.write_message(message)?;
+        self.index_message_terms(chat_id, &id, &message.content)?;
+
+        Ok(id)
+    }
+
+    // Write the message file without touching the indexes; bulk writers
+    // rebuild those once at the end instead
+    fn write_message(&self, message: &Message) -> Result<String, Box<dyn std::error::Error>> {
+        let id = message_id(message)?;
+        let mut message = message.clone();
+        message.id = Some(id.clone());
+        let path = format!("{}/{}.json", self.chat_path(), id);
+        write_file(&path, &serde_json::to_string(&message)?)?;
+
+        Ok(id)
+    }
+
+    fn load_message(&self, id: &str) -> Result<Message, Box<dyn std::error::Error>> {
+        // Ids are sha1 digests; anything else could name a file outside the store
+        let valid = id.len() == 40 && id.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'));
+        if !valid {
+            return Err(format!("Message not found: {}", id).into());
+        }
+        let path = format!("{}/{}.json", self.chat_path(), id);
+        let content = read_file(&path)?;
+        Ok(serde_json::from_slice(&content)?)
+    }
+
+    // Each chat keeps its record in a file of its own, so a child actor
+    // updating one chat never rewrites another's
+    fn records_path(&self) -> String {
+        format!("{}/records", self.chat_path())
+    }
+
+    fn chat_record_path(&self, id: &str) -> String {
+        format!("{}/{}.json", self.records_path(), id)
+    }
+
+    // Oldest first
+    fn get_all_chats(&self) -> Result<Vec<Chat>, Box<dyn std::error::Error>> {
+        let mut chats = Vec::new();
+        for path in list_files(&self.records_path())? {
+            let name = path.rsplit('/').next().unwrap_or_default();
+            let Some(id) = name.strip_suffix(".json") else {
+                continue;
+            };
+            match self.load_chat(id) {
+                Ok(chat) => chats.push(chat),
+                Err(e) => log(&format!("Error loading chat {}: {}", id, e)),
+            }
+        }
+        chats.sort_by(|a, b| a.created_at.cmp(&b.created_at).then(a.id.cmp(&b.id)));
+        Ok(chats)
+    }
+
+    fn load_chat(&self, id: &str) -> Result<Chat, Box<dyn std::error::Error>> {
+        let valid = !id.is_empty()
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid || !path_exists(&self.chat_record_path(id))? {
+            return Err(format!("Chat not found: {}", id).into());
+        }
+        let content = read_file(&self.chat_record_path(id))?;
+        Ok(serde_json::from_slice(&content)?)
+    }
+
+    // Insert or replace the chat record with the same id
+    fn save_chat(&self, chat: &Chat) -> Result<(), Box<dyn std::error::Error>> {
+        write_file(
+            &self.chat_record_path(&chat.id),
+            &serde_json::to_string(chat)?,
+        )?;
+        Ok(())
+    }
+
+    fn generate_chat_id(&self, title: &str) -> Result<String, Box<dyn std::error::Error>> {
+        self.new_chat_id(title, &self.get_all_chats()?)
+    }
+
+    fn chat_sequence_path(&self) -> String {
+        format!("{}/chat-sequence.txt", self.chat_path())
+    }
+
+    // Chat ids hash the title with a stored sequence number that only grows, so
+    // a deleted chat's id is never handed out again and its usage stays its own
+    fn new_chat_id(
+        &self,
+        title: &str,
+        chats: &[Chat],
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let mut sequence: u64 = if path_exists(&self.chat_sequence_path())? {
+            String::from_utf8_lossy(&read_file(&self.chat_sequence_path())?)
+                .trim()
+                .parse()?
+        } else {
+            0
+        };
+        loop {
+            sequence += 1;
+            let mut hasher = Sha1::new();
+            hasher.update(format!("chat:{}:{}", sequence, title).as_bytes());
+            let id = format!("{:x}", hasher.finalize())[..12].to_string();
+            if !chats.iter().any(|chat| chat.id == id) {
+                write_file(&self.chat_sequence_path(), &sequence.to_string())?;
+                return Ok(id);
+            }
+        }
+    }
+
+    // Chats for the sidebar: pinned first, then most recently active
+    fn list_chats(&self, include_archived: bool) -> Result<Vec<Chat>, Box<dyn std::error::Error>> {
+        let mut chats: Vec<Chat> = self
+            .get_all_chats()?
+            .into_iter()
+            .filter(|chat| include_archived || !chat.archived)
+            .collect();
+        chats.sort_by(|a, b| {
+            b.pinned
+                .cmp(&a.pinned)
+                .then(b.updated_at.cmp(&a.updated_at))
+        });
+        Ok(chats)
+    }
+
+    fn update_chat(
+        &self,
+        id: &str,
+        update: impl FnOnce(&mut Chat),
+    ) -> Result<Chat, Box<dyn std::error::Error>> {
+        let mut chat = self.load_chat(id)?;
+        update(&mut chat);
+        self.save_chat(&chat)?;
+        Ok(chat)
+    }
+
+    fn rename_chat(&self, id: &str, title: &str) -> Result<Chat, Box<dyn std::error::Error>> {
+        self.update_chat(id, |chat| chat.title = title.to_string())
+    }
+
+    fn pin_chat(&self, id: &str, pinned: bool) -> Result<Chat, Box<dyn std::error::Error>> {
+        self.update_chat(id, |chat| chat.pinned = pinned)
+    }
+
+    fn archive_chat(&self, id: &str, archived: bool) -> Result<Chat, Box<dyn std::error::Error>> {
+        self.update_chat(id, |chat| chat.archived = archived)
+    }
+
+    // Advance the clock to the caller's timestamp, or one step past the last
+    // one when the caller didn't send any
+    fn tick(&mut self, timestamp: Option<u64>) {
+        self.clock = timestamp.unwrap_or(0).max(self.clock + 1);
+    }
+
+    // Move the clock up to a timestamp observed elsewhere, such as a provider's
+    // Date header, without ever moving it back
+    fn sync_clock(&mut self, timestamp: Option<u64>) {
+        if let Some(timestamp) = timestamp {
+            self.clock = self.clock.max(timestamp);
+            self.provider_time = self.provider_time.max(timestamp);
+        }
+    }
+
+    // Remove a chat and reclaim the messages no other chat can reach,
+    // returning how many message files were deleted. The chat's usage stays
+    // in the ledger.
+    fn delete_chat(&mut self, id: &str) -> Result<usize, Box<dyn std::error::Error>> {
+        self.load_chat(id)?;
+        self.forget_chat_actor(id);
+        delete_file(&self.chat_record_path(id))?;
+        for path in [self.tree_path(id), self.search_index_path(id)] {
+            if path_exists(&path)? {
+                if let Err(e) = delete_file(&path) {
+                    log(&format!("Error removing {}: {}", path, e));
+                }
+            }
+        }
+        self.collect_garbage()
+    }
+
+    // Stores from before chat ids listed titles in chats.txt, with one
+    // `{title}.json` file per chat. Each chat moves to an id-keyed record.
+    fn migrate_title_keyed_chats(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let legacy = format!("{}/chats.txt", self.chat_path());
+        let titles: Vec<String> = serde_json::from_slice(&read_file(&legacy)?)?;
+        log(&format!("Migrating {} title-keyed chats", titles.len()));
+
+        let mut chats: Vec<Chat> = Vec::new();
+        let mut migrated = Vec::new();
+        for title in titles {
+            let path = format!("{}/{}.json", self.chat_path(), title);
+            let mut chat: Value = match read_file(&path) {
+                Ok(content) => serde_json::from_slice(&content)?,
+                Err(e) => {
+                    log(&format!("Error reading chat {}: {}", title, e));
+                    continue;
+                }
+            };
+            chat["id"] = json!(self.new_chat_id(&title, &chats)?);
+            chats.push(serde_json::from_value(chat)?);
+            migrated.push(path);
+        }
+        for chat in &chats {
+            self.save_chat(chat)?;
+        }
+
+        for path in migrated {
+            if let Err(e) = delete_file(&path) {
+                log(&format!(
+                    "Error removing migrated chat file {}: {}",
+                    path, e
+                ));
+            }
+        }
+        delete_file(&legacy)?;
+        Ok(())
+    }
+
+    // Mark every message in the tree of each remaining chat, then sweep the rest.
+    // Marking whole trees rather than only the head chains keeps sibling branches
+    // that a chat can still switch back to. Files are listed before the trees
+    // are read, so a message stored meanwhile is never swept.
+    fn collect_garbage(&self) -> Result<usize, Box<dyn std::error::Error>> {
+        let ids = self.list_message_ids()?;
+        let mut reachable = HashSet::new();
+        for chat in self.get_all_chats()? {
+            for message in self.get_message_chain(chat.head.clone()) {
+                reachable.extend(message.id);
+            }
+            reachable.extend(self.load_tree_index(&chat.id)?.ids().cloned());
+        }
+
+        let mut reclaimed = 0;
+        let mut attachments = HashSet::new();
+        for id in ids {
+            if reachable.contains(&id) {
+                continue;
+            }
+            if let Ok(message) = self.load_message(&id) {
+                attachments.extend(message.attachments.into_iter().map(|a| a.id));
+            }
+            match delete_file(&format!("{}/{}.json", self.chat_path(), id)) {
+                Ok(()) => reclaimed += 1,
+                Err(e) => log(&format!("Error deleting message {}: {}", id, e)),
+            }
+        }
 
-    // Create necessary directories
+        log(&format!("Reclaimed {} unreachable messages", reclaimed));
+        if !attachments.is_empty() {
+            self.sweep_attachments(attachments, &reachable)?;
+        }
+        Ok(reclaimed)
+    }
+
+    // Walk from the head back to the root via parent links, returning root first
+    fn get_message_chain(&self, head: Option<String>) -> Vec<Message> {
+        let mut messages = Vec::new();
+        let mut current = head;
+        while let Some(id) = current {
+            match self.load_message(&id) {
+                Ok(message) => {
+                    current = message.parent.clone();
+                    messages.push(message);
+                }
+                Err(e) => {
+                    log(&format!("Error loading message {}: {}", id, e));
+                    break;
+                }
+            }
+        }
+        messages.reverse();
+        messages
+    }
+
+    fn get_all_messages(&self) -> Result<Vec<Message>, Box<dyn std::error::Error>> {
+        let mut messages = Vec::new();
+        for chat in self.get_all_chats()? {
+            messages.extend(self.get_message_chain(chat.head));
+        }
+        Ok(messages)
+    }
+
+    fn create_chat(
+        &self,
+        title: &str,
+        provider: Option<String>,
+    ) -> Result<Chat, Box<dyn std::error::Error>> {
+        if let Some(name) = &provider {
+            self.provider_config(name)?;
+        }
+        let chat = Chat {
+            id: self.generate_chat_id(title)?,
+            title: title.to_string(),
+            head: None,
+            provider,
+            settings: ChatSettings::default(),
+            system_prompt: None,
+            pinned: false,
+            archived: false,
+            created_at: self.clock,
+            updated_at: self.clock,
+        };
+        self.save_chat(&chat)?;
+        Ok(chat)
+    }
+
+    // Start a new chat whose head is an existing message, optionally inheriting
+    // the provider, settings and system prompt of the chat it was forked from
+    fn fork_chat(
+        &self,
+        title: &str,
+        message_id: &str,
+        source_chat: Option<&str>,
+    ) -> Result<Chat, Box<dyn std::error::Error>> {
+        self.load_message(message_id)?;
+        let mut chat = match source_chat {
+            Some(source) => Chat {
+                id: self.generate_chat_id(title)?,
+                pinned: false,
+                archived: false,
+                created_at: self.clock,
+                updated_at: self.clock,
+                ..self.load_chat(source)?
+            },
+            None => self.create_chat(title, None)?,
+        };
+        chat.title = title.to_string();
+        chat.head = Some(message_id.to_string());
+        self.save_chat(&chat)?;
+
+        // The fork starts from the chain it branched off, not the source's other replies
+        let mut tree = TreeIndex::default();
+        for message in self.get_message_chain(chat.head.clone()) {
+            if let Some(id) = &message.id {
+                tree.insert(id, message.parent.as_deref());
+            }
+        }
+        self.write_tree_index(&chat.id, &tree)?;
+        Ok(chat)
+    }
+
+    // Message files are named by their 40 character sha1 id
+    fn list_message_ids(&self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let ids = list_files(&self.chat_path())?
+            .into_iter()
+            .filter_map(|path| {
+                let name = path.rsplit('/').next().unwrap_or_default();
+                let id = name.strip_suffix(".json")?;
+                (id.len() == 40 && id.chars().all(|c| c.is_ascii_hexdigit()))
+                    .then(|| id.to_string())
+            })
+            .collect();
+        Ok(ids)
+    }
+
+    fn tree_path(&self, chat_id: &str) -> String {
+        format!("{}/trees/{}.json", self.chat_path(), chat_id)
+    }
+
+    // A chat with no messages yet has no tree file
+    fn load_tree_index(&self, chat_id: &str) -> Result<TreeIndex, Box<dyn std::error::Error>> {
+        if !path_exists(&self.tree_path(chat_id))? {
+            return Ok(TreeIndex::default());
+        }
+        let content = read_file(&self.tree_path(chat_id))?;
+        Ok(serde_json::from_slice(&content)?)
+    }
+
+    fn write_tree_index(
+        &self,
+        chat_id: &str,
+        tree: &TreeIndex,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        write_file(&self.tree_path(chat_id), &serde_json::to_string(tree)?)?;
+        Ok(())
+    }
+
+    fn index_tree_message(
+        &self,
+        chat_id: &str,
+        id: &str,
+        parent: Option<&str>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut tree = self.load_tree_index(chat_id)?;
+        if tree.insert(id, parent) {
+            self.write_tree_index(chat_id, &tree)?;
+        }
+        Ok(())
+    }
+
+    fn chat_contains(
+        &self,
+        chat_id: &str,
+        message_id: &str,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        Ok(self.load_tree_index(chat_id)?.contains(message_id))
+    }
+
+    // Replies to a message from every chat that holds it
+    fn get_children(&self, id: &str) -> Result<Vec<Message>, Box<dyn std::error::Error>> {
+        let mut ids: Vec<String> = Vec::new();
+        for chat in self.get_all_chats()? {
+            for child in self
+                .load_tree_index(&chat.id)?
+                .children
+                .remove(id)
+                .into_iter()
+                .flatten()
+            {
+                if !ids.contains(&child) {
+                    ids.push(child);
+                }
+            }
+        }
+        ids.iter().map(|child| self.load_message(child)).collect()
+    }
+
+    fn get_chat_tree(&self, chat: &Chat) -> Result<ChatTree, Box<dyn std::error::Error>> {
+        let root = self
+            .get_message_chain(chat.head.clone())
+            .first()
+            .and_then(|message| message.id.clone());
+
+        // A message another actor is still writing may not have its file yet
+        let index = self.load_tree_index(&chat.id)?;
+        let mut messages = Vec::new();
+        let mut pending: Vec<&String> = index.roots.iter().collect();
+        while let Some(id) = pending.pop() {
+            match self.load_message(id) {
+                Ok(message) => messages.push(message),
+                Err(e) => log(&format!("Error loading message {}: {}", id, e)),
+            }
+            pending.extend(index.children.get(id).into_iter().flatten());
+        }
+
+        Ok(ChatTree {
+            root,
+            children: index.children,
+            messages,
+        })
+    }
+
+    // Chats from before branching have a single chain, which becomes their tree
+    fn seed_tree_indexes(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let chats = self.get_all_chats()?;
+        log(&format!("Building trees for {} chats", chats.len()));
+        for chat in chats {
+            let mut tree = TreeIndex::default();
+            for message in self.get_message_chain(chat.head.clone()) {
+                if let Some(id) = &message.id {
+                    tree.insert(id, message.parent.as_deref());
+                }
+            }
+            self.write_tree_index(&chat.id, &tree)?;
+        }
+        Ok(())
+    }
+
+    fn set_head(
+        &self,
+        chat_id: &str,
+        message_id: &str,
+    ) -> Result<Chat, Box<dyn std::error::Error>> {
+        let mut chat = self.load_chat(chat_id)?;
+        if !self.chat_contains(chat_id, message_id)? {
+            return Err(format!("Message {} is not part of chat {}", message_id, chat_id).into());
+        }
+        chat.head = Some(message_id.to_string());
+        self.save_chat(&chat)?;
+        Ok(chat)
+    }
+
+    fn provider_config(&self, name: &str) -> Result<&ProviderConfig, Box<dyn std::error::Error>> {
+        self.providers
+            .get(name)
+            .ok_or_else(|| format!("Unknown provider: {}", name).into())
+    }
+
+    // The chat's own prompt takes precedence over the actor-wide default
+    fn system_prompt<'a>(&'a self, chat: &'a Chat) -> Option<&'a str> {
+        chat.system_prompt
+            .as_deref()
+            .or(self.default_system_prompt.as_deref())
+    }
+
+    fn set_default_system_prompt(
+        &mut self,
+        prompt: Option<String>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        write_file(SYSTEM_PROMPT_PATH, prompt.as_deref().unwrap_or_default())?;
+        self.default_system_prompt = prompt;
+        Ok(())
+    }
+
+    // Request a streamed completion from the chat's provider
+    fn complete(
+        &self,
+        chat: &Chat,
+        context: &Context,
+        tools: &[ToolDefinition],
+    ) -> Result<Completion, Box<dyn std::error::Error>> {
+        let name = chat.provider.as_deref().unwrap_or(&self.default_provider);
+        let mut messages = context.messages.clone();
+        self.load_attachment_data(&mut messages);
+        self.complete_with(
+            Some(&chat.id),
+            name,
+            &messages,
+            &chat.settings,
+            context.system.as_deref(),
+            tools,
+        )
+    }
+
+    // Every provider call goes through here so the usage ledger sees all of
+    // them, including titles and summaries
+    fn complete_with(
+        &self,
+        chat_id: Option<&str>,
+        provider_name: &str,
+        messages: &[Message],
+        settings: &ChatSettings,
+        system: Option<&str>,
+        tools: &[ToolDefinition],
+    ) -> Result<Completion, Box<dyn std::error::Error>> {
+        let config = self.provider_config(provider_name)?;
+        let provider = config.provider();
+
+        let request = provider.build_request(messages, settings, system, tools)?;
+        let response = send_http(&request);
+        let mut completion = provider.parse_response(&response)?;
+
+        completion.timestamp = response_date(&response);
+        // Without a Date header, book the usage on the last provider day seen
+        // rather than on whatever the client claimed
+        let timestamp = completion
+            .timestamp
+            .or(Some(self.provider_time).filter(|&time| time > 0))
+            .unwrap_or(self.clock);
+        let model = settings.model.as_deref().unwrap_or(config.model());
+        if let Err(e) =
+            self.record_usage(timestamp, chat_id, provider_name, model, &completion.usage)
+        {
+            log(&format!("Error recording usage: {}", e));
+        }
+
+        Ok(completion)
+    }
+
+    // Ensure necessary directories exist
     fn ensure_directories(&self) -> Result<(), Box<dyn std::error::Error>> {
         // Create main chat directory
-        let base_path = format!("{}/data/{}", self.base_directory, self.chat_directory);
+        let base_path = self.chat_path();
         log(&format!("Creating directory at: {}", base_path));
-        create_dir(&base_path)?;
-        
-        // Initialize chats.txt if it doesn't exist
-        let chats_path = format!("{}/chats.txt", base_path);
-        if !path_exists(&chats_path).expect("Failed to check path existence") {
-            log(&format!("Initializing chats.txt at: {}", chats_path));
-            write_file(&chats_path, &serde_json::to_string(&Vec::<String>::new())?).unwrap();
-        }
-        
+        if !path_exists(&base_path)? {
+            create_dir(&base_path)?;
+        }
+
+        // Chat records, trees, search indexes and usage each get a file per
+        // chat; title-keyed stores are migrated the first time this version runs
+        if !path_exists(&self.records_path())? {
+            log(&format!(
+                "Initializing chat records at: {}",
+                self.records_path()
+            ));
+            create_dir(&self.records_path())?;
+            if path_exists(&format!("{}/chats.txt", self.chat_path()))? {
+                self.migrate_title_keyed_chats()?;
+            }
+        }
+
+        let trees_path = format!("{}/trees", self.chat_path());
+        if !path_exists(&trees_path)? {
+            create_dir(&trees_path)?;
+            self.seed_tree_indexes()?;
+        }
+
+        if !self.search_index_exists() {
+            log("Building search index");
+            self.rebuild_search_index()?;
+        }
+
+        self.ensure_ledgers()?;
+
+        if !path_exists(TOOL_ROOT)? {
+            create_dir(TOOL_ROOT)?;
+        }
+
         Ok(())
     }
 }
 
+// Messages are content addressed by the sha1 of their serialized form
+fn message_id(message: &Message) -> Result<String, serde_json::Error> {
+    let mut message = message.clone();
+    message.id = None;
+    let mut hasher = Sha1::new();
+    hasher.update(serde_json::to_vec(&message)?);
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn text_message(value: Value) -> WebsocketMessage {
+    WebsocketMessage {
+        ty: MessageType::Text,
+        data: None,
+        text: Some(value.to_string()),
+    }
+}
+
+fn error_message(message: &str) -> WebsocketMessage {
+    text_message(json!({
+        "status": "error",
+        "message": message,
+    }))
+}
+
+// Limit errors carry the limit that was hit so clients can explain or back off
+fn limit_message(error: &LimitExceeded) -> WebsocketMessage {
+    text_message(error.to_json())
+}
+
+// Clients may identify themselves for per-client rate limits; anonymous ones share a bucket
+fn request_client(req: &HttpRequest, fallback: &str) -> String {
+    req.headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("x-client-id"))
+        .map_or_else(|| fallback.to_string(), |(_, value)| value.clone())
+}
+
+// The provider's Date header is the only wall clock available to the actor
+fn response_date(response: &HttpResponse) -> Option<u64> {
+    response
+        .headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("date"))
+        .and_then(|(_, value)| parse_http_date(value))
+}
+
+fn json_response(status: u16, body: &Value) -> HttpResponse {
+    HttpResponse {
+        status,
+        headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+        body: Some(serde_json::to_vec(body).unwrap()),
+    }
+}
+
+fn error_response(status: u16, message: &str) -> HttpResponse {
+    json_response(status, &json!({ "status": "error", "message": message }))
+}
+
+fn static_response(path: &str, content_type: &str) -> HttpResponse {
+    match read_file(path) {
+        Ok(content) => HttpResponse {
+            status: 200,
+            headers: vec![("Content-Type".to_string(), content_type.to_string())],
+            body: Some(content),
+        },
+        Err(e) => {
+            log(&format!("Error reading {}: {}", path, e));
+            HttpResponse {
+                status: 404,
+                headers: vec![],
+                body: Some(b"Not Found".to_vec()),
+            }
+        }
+    }
+}
+
 impl ActorGuest for Component {
     fn init() -> Vec<u8> {
         log("Initializing unified chat actor");
 
-        // Get the current directory
-        let base_directory = match bindings::ntwk::theater::filesystem::current_dir() {
-            Ok(dir) => dir,
+        // Paths are resolved relative to the filesystem handler's root
+        let base_directory = ".".to_string();
+        log(&format!("Base directory: {}", base_directory));
+
+        // Read API key
+        let api_key = match read_file("api-key.txt") {
+            Ok(content) => String::from_utf8(content).unwrap().trim().to_string(),
             Err(e) => {
-                log(&format!("Error getting current directory: {}", e));
-                "/".to_string()
+                log(&format!("Error reading api-key.txt: {}", e));
+                String::new()
             }
         };
-        log(&format!("Base directory: {}", base_directory));
 
-        // Read API key
-        let api_key = read_file("api-key.txt").unwrap();
-        let api_key = String::from_utf8(api_key).unwrap().trim().to_string();
+        // Anthropic is always available; providers.json can add or override entries
+        let mut providers = HashMap::new();
+        providers.insert(
+            DEFAULT_PROVIDER.to_string(),
+            ProviderConfig::Anthropic(AnthropicProvider {
+                api_key,
+                model: MODEL.to_string(),
+                title_model: Some(TITLE_MODEL.to_string()),
+            }),
+        );
+        if path_exists("providers.json").unwrap_or(false) {
+            match read_file("providers.json")
+                .map_err(|e| e.to_string())
+                .and_then(|content| {
+                    serde_json::from_slice::<HashMap<String, ProviderConfig>>(&content)
+                        .map_err(|e| e.to_string())
+                }) {
+                Ok(configured) => providers.extend(configured),
+                Err(e) => log(&format!("Error loading providers.json: {}", e)),
+            }
+        }
 
-        let initial_state = State {
+        // Built-in list prices; rates.json can add or override models
+        let mut rates = default_rates();
+        if path_exists(RATES_PATH).unwrap_or(false) {
+            match read_file(RATES_PATH)
+                .map_err(|e| e.to_string())
+                .and_then(|content| {
+                    serde_json::from_slice::<HashMap<String, Rate>>(&content)
+                        .map_err(|e| e.to_string())
+                }) {
+                Ok(configured) => rates.extend(configured),
+                Err(e) => log(&format!("Error loading {}: {}", RATES_PATH, e)),
+            }
+        }
+
+        let limits = match read_file(LIMITS_PATH) {
+            Ok(content) => serde_json::from_slice::<Limits>(&content).unwrap_or_else(|e| {
+                log(&format!("Error loading {}: {}", LIMITS_PATH, e));
+                Limits::default()
+            }),
+            Err(_) => Limits::default(),
+        };
+
+        let chat_actors = match read_file(CHAT_ACTORS_PATH) {
+            Ok(content) => match serde_json::from_slice::<ChatActorConfig>(&content) {
+                Ok(config) => Some(config),
+                Err(e) => {
+                    log(&format!("Error loading {}: {}", CHAT_ACTORS_PATH, e));
+                    None
+                }
+            },
+            Err(_) => None,
+        };
+
+        let default_system_prompt = read_file(SYSTEM_PROMPT_PATH)
+            .ok()
+            .map(|content| String::from_utf8_lossy(&content).trim().to_string())
+            .filter(|prompt| !prompt.is_empty());
+
+        let mut initial_state = State {
             chat_directory: "chats".to_string(),
             base_directory,
-            api_key,
-            connected_clients: HashMap::new(),
+            providers,
+            default_provider: DEFAULT_PROVIDER.to_string(),
+            default_system_prompt,
+            clock: 0,
+            provider_time: 0,
+            rates,
+            limits,
+            request_log: HashMap::new(),
+            chat_actors,
+            chat_actor_ids: HashMap::new(),
         };
 
         // Ensure directories exist
@@ -51,8 +1027,826 @@ impl ActorGuest for Component {
             log(&format!("Error ensuring directories exist: {}", e));
         }
 
+        // Resume the clock from the most recent activity on record
+        if let Ok(chats) = initial_state.get_all_chats() {
+            initial_state.clock = chats.iter().map(|chat| chat.updated_at).max().unwrap_or(0);
+        }
+
         serde_json::to_vec(&initial_state).unwrap()
     }
 }
 
-// [Rest of the file remains the same...]
\ No newline at end of file
+impl HttpGuest for Component {
+    fn handle_request(req: HttpRequest, state: Vec<u8>) -> (HttpResponse, Vec<u8>) {
+        log(&format!(
+            "Handling HTTP request: {} {}",
+            req.method, req.uri
+        ));
+        let mut current_state: State = serde_json::from_slice(&state).unwrap();
+        current_state.tick(None);
+
+        let path = req.uri.split('?').next().unwrap_or_default();
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+        let response = match (req.method.as_str(), segments.as_slice()) {
+            ("GET", []) | ("GET", ["index.html"]) => static_response("index.html", "text/html"),
+            ("GET", ["styles.css"]) => static_response("styles.css", "text/css"),
+            ("GET", ["chat.js"]) => static_response("chat.js", "application/javascript"),
+            (_, ["api", rest @ ..]) => api::handle_api(&mut current_state, &req, rest),
+            ("GET", ["v1", "models"]) => openai::handle_models(&current_state),
+            ("POST", ["v1", "chat", "completions"]) => {
+                openai::handle_chat_completions(&mut current_state, &req)
+            }
+            _ => HttpResponse {
+                status: 404,
+                headers: vec![],
+                body: Some(b"Not Found".to_vec()),
+            },
+        };
+
+        (response, serde_json::to_vec(&current_state).unwrap())
+    }
+}
+
+impl WebSocketGuest for Component {
+    fn handle_message(message: WebsocketMessage, state: Vec<u8>) -> (Vec<u8>, WebsocketResponse) {
+        let mut current_state: State = serde_json::from_slice(&state).unwrap();
+        let mut responses = Vec::new();
+
+        match message.ty {
+            MessageType::Connect => log("WebSocket client connected"),
+            MessageType::Close => log("WebSocket client disconnected"),
+            MessageType::Text => {
+                let text = message.text.unwrap_or_default();
+                match serde_json::from_str::<Value>(&text) {
+                    Ok(command) => {
+                        current_state.tick(command["timestamp"].as_u64());
+                        handle_command(&mut current_state, &command, &mut responses)
+                    }
+                    Err(e) => {
+                        log(&format!("Error parsing WebSocket message: {}", e));
+                        responses.push(error_message("Invalid JSON"));
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        (
+            serde_json::to_vec(&current_state).unwrap(),
+            WebsocketResponse {
+                messages: responses,
+            },
+        )
+    }
+}
+
+fn handle_command(state: &mut State, command: &Value, responses: &mut Vec<WebsocketMessage>) {
+    // Chat lists in responses leave out archived chats unless the client asks for them
+    let include_archived = command["include_archived"].as_bool().unwrap_or(false);
+    let client = command["client_id"].as_str().unwrap_or("websocket");
+
+    match command["type"].as_str() {
+        Some("get_all") => match (state.list_chats(include_archived), state.get_all_messages()) {
+            (Ok(chats), Ok(messages)) => responses.push(text_message(json!({
+                "type": "chats_update",
+                "status": "success",
+                "chats": chats,
+                "messages": messages,
+                "providers": state.providers.keys().collect::<Vec<_>>(),
+            }))),
+            (Err(e), _) | (_, Err(e)) => responses.push(error_message(&e.to_string())),
+        },
+        Some("new_chat") => {
+            let title = command["title"].as_str().unwrap_or(DEFAULT_TITLE);
+            let provider = command["provider"].as_str().map(String::from);
+            match state
+                .create_chat(title, provider)
+                .and_then(|chat| Ok((chat, state.list_chats(include_archived)?)))
+            {
+                Ok((chat, chats)) => responses.push(text_message(json!({
+                    "type": "chats_update",
+                    "status": "success",
+                    "chat_id": chat.id,
+                    "chats": chats,
+                }))),
+                Err(e) => responses.push(error_message(&e.to_string())),
+            }
+        }
+        Some("rename_chat") => {
+            let chat_id = command["chat_id"].as_str().unwrap_or_default();
+            let title = command["title"].as_str().unwrap_or_default();
+            match state
+                .rename_chat(chat_id, title)
+                .and_then(|_| state.list_chats(include_archived))
+            {
+                Ok(chats) => responses.push(text_message(json!({
+                    "type": "chats_update",
+                    "status": "success",
+                    "chats": chats,
+                }))),
+                Err(e) => responses.push(error_message(&e.to_string())),
+            }
+        }
+        Some("pin_chat") => {
+            let chat_id = command["chat_id"].as_str().unwrap_or_default();
+            let pinned = command["pinned"].as_bool().unwrap_or(true);
+            match state
+                .pin_chat(chat_id, pinned)
+                .and_then(|_| state.list_chats(include_archived))
+            {
+                Ok(chats) => responses.push(text_message(json!({
+                    "type": "chats_update",
+                    "status": "success",
+                    "chats": chats,
+                }))),
+                Err(e) => responses.push(error_message(&e.to_string())),
+            }
+        }
+        Some("archive_chat") => {
+            let chat_id = command["chat_id"].as_str().unwrap_or_default();
+            let archived = command["archived"].as_bool().unwrap_or(true);
+            match state
+                .archive_chat(chat_id, archived)
+                .and_then(|_| state.list_chats(include_archived))
+            {
+                Ok(chats) => responses.push(text_message(json!({
+                    "type": "chats_update",
+                    "status": "success",
+                    "chats": chats,
+                }))),
+                Err(e) => responses.push(error_message(&e.to_string())),
+            }
+        }
+        Some("delete_chat") => {
+            let chat_id = command["chat_id"].as_str().unwrap_or_default();
+            match state
+                .delete_chat(chat_id)
+                .and_then(|reclaimed| Ok((reclaimed, state.list_chats(include_archived)?)))
+            {
+                Ok((reclaimed, chats)) => responses.push(text_message(json!({
+                    "type": "chats_update",
+                    "status": "success",
+                    "chats": chats,
+                    "reclaimed": reclaimed,
+                }))),
+                Err(e) => responses.push(error_message(&e.to_string())),
+            }
+        }
+        Some("get_settings") => {
+            let chat_id = command["chat_id"].as_str().unwrap_or_default();
+            match state.load_chat(chat_id) {
+                Ok(chat) => responses.push(text_message(json!({
+                    "type": "settings",
+                    "status": "success",
+                    "chat_id": chat_id,
+                    "settings": chat.settings,
+                }))),
+                Err(e) => responses.push(error_message(&e.to_string())),
+            }
+        }
+        Some("update_settings") => {
+            let chat_id = command["chat_id"].as_str().unwrap_or_default();
+            let result = serde_json::from_value::<ChatSettings>(command["settings"].clone())
+                .map_err(|e| e.into())
+                .and_then(|settings| update_settings(state, chat_id, settings));
+            match result {
+                Ok(chat) => responses.push(text_message(json!({
+                    "type": "settings",
+                    "status": "success",
+                    "chat_id": chat_id,
+                    "settings": chat.settings,
+                }))),
+                Err(e) => responses.push(error_message(&e.to_string())),
+            }
+        }
+        Some("get_tools") => {
+            let tools: Vec<ToolDefinition> = builtin_tools()
+                .into_iter()
+                .map(|tool| tool.definition)
+                .collect();
+            responses.push(text_message(json!({
+                "type": "tools",
+                "status": "success",
+                "tools": tools,
+            })));
+        }
+        Some("get_system_prompt") => match command["chat_id"].as_str() {
+            Some(chat_id) => match state.load_chat(chat_id) {
+                Ok(chat) => responses.push(text_message(json!({
+                    "type": "system_prompt",
+                    "status": "success",
+                    "chat_id": chat_id,
+                    "system_prompt": chat.system_prompt,
+                    "effective": state.system_prompt(&chat),
+                }))),
+                Err(e) => responses.push(error_message(&e.to_string())),
+            },
+            None => responses.push(text_message(json!({
+                "type": "system_prompt",
+                "status": "success",
+                "system_prompt": state.default_system_prompt,
+            }))),
+        },
+        Some("set_system_prompt") => {
+            let prompt = prompt_value(&command["system_prompt"]);
+            match command["chat_id"].as_str() {
+                Some(chat_id) => match set_system_prompt(state, chat_id, prompt) {
+                    Ok(chat) => responses.push(text_message(json!({
+                        "type": "system_prompt",
+                        "status": "success",
+                        "chat_id": chat_id,
+                        "system_prompt": chat.system_prompt,
+                    }))),
+                    Err(e) => responses.push(error_message(&e.to_string())),
+                },
+                None => match state.set_default_system_prompt(prompt) {
+                    Ok(()) => responses.push(text_message(json!({
+                        "type": "system_prompt",
+                        "status": "success",
+                        "system_prompt": state.default_system_prompt,
+                    }))),
+                    Err(e) => responses.push(error_message(&e.to_string())),
+                },
+            }
+        }
+        Some("set_provider") => {
+            let chat_id = command["chat_id"].as_str().unwrap_or_default();
+            let provider = command["provider"].as_str().unwrap_or_default();
+            match set_provider(state, chat_id, provider)
+                .and_then(|_| state.list_chats(include_archived))
+            {
+                Ok(chats) => responses.push(text_message(json!({
+                    "type": "chats_update",
+                    "status": "success",
+                    "chats": chats,
+                }))),
+                Err(e) => responses.push(error_message(&e.to_string())),
+            }
+        }
+        Some("send_message") => {
+            let chat_id = command["chat_id"].as_str().unwrap_or_default();
+            let content = command["content"].as_str().unwrap_or_default();
+            let parent_id = command["parent_id"].as_str();
+            if let Err(e) = state.check_limits(client) {
+                log(&format!("Rejected message from {}: {}", client, e));
+                responses.push(limit_message(&e));
+            } else if state.chat_actors.is_some() {
+                queue_in_chat_actor(state, chat_id, command, responses);
+            } else if let Err(e) =
+                state
+                    .resolve_attachments(&command["attachments"])
+                    .and_then(|attachments| {
+                        send_message(state, chat_id, content, parent_id, attachments, responses)
+                    })
+            {
+                log(&format!("Error sending message: {}", e));
+                responses.push(error_message(&e.to_string()));
+            }
+        }
+        Some("regenerate") => {
+            let chat_id = command["chat_id"].as_str().unwrap_or_default();
+            let message_id = command["message_id"].as_str().unwrap_or_default();
+            if let Err(e) = state.check_limits(client) {
+                log(&format!("Rejected regeneration from {}: {}", client, e));
+                responses.push(limit_message(&e));
+            } else if state.chat_actors.is_some() {
+                queue_in_chat_actor(state, chat_id, command, responses);
+            } else if let Err(e) = regenerate(state, chat_id, message_id, responses) {
+                log(&format!("Error regenerating message: {}", e));
+                responses.push(error_message(&e.to_string()));
+            }
+        }
+        Some("restart_chat_actor") => {
+            let chat_id = command["chat_id"].as_str().unwrap_or_default();
+            match state.spawn_chat_actor(chat_id) {
+                Ok(actor_id) => responses.push(text_message(json!({
+                    "type": "chat_actor",
+                    "status": "success",
+                    "chat_id": chat_id,
+                    "actor_id": actor_id,
+                }))),
+                Err(e) => responses.push(error_message(&e.to_string())),
+            }
+        }
+        Some("search") => {
+            let query = command["query"].as_str().unwrap_or_default();
+            let limit = command["limit"]
+                .as_u64()
+                .map_or(SEARCH_LIMIT, |n| n as usize);
+            match state.search(query, limit) {
+                Ok(results) => responses.push(text_message(json!({
+                    "type": "search_results",
+                    "status": "success",
+                    "query": query,
+                    "results": results,
+                }))),
+                Err(e) => responses.push(error_message(&e.to_string())),
+            }
+        }
+        Some("reindex") => match state.rebuild_search_index() {
+            Ok(indexed) => responses.push(text_message(json!({
+                "type": "reindexed",
+                "status": "success",
+                "indexed": indexed,
+            }))),
+            Err(e) => responses.push(error_message(&e.to_string())),
+        },
+        Some("get_usage") => {
+            let query = UsageQuery {
+                from: command["from"].as_str(),
+                to: command["to"].as_str(),
+                chat_id: command["chat_id"].as_str(),
+            };
+            match state.usage_report(&query) {
+                Ok(report) => responses.push(text_message(json!({
+                    "type": "usage",
+                    "status": "success",
+                    "usage": report,
+                }))),
+                Err(e) => responses.push(error_message(&e.to_string())),
+            }
+        }
+        Some("list_branches") => {
+            let chat_id = command["chat_id"].as_str().unwrap_or_default();
+            match state.load_chat(chat_id).and_then(|chat| {
+                let tree = state.get_chat_tree(&chat)?;
+                Ok((chat, tree))
+            }) {
+                Ok((chat, tree)) => responses.push(text_message(json!({
+                    "type": "branches",
+                    "status": "success",
+                    "chat_id": chat_id,
+                    "head": chat.head,
+                    "root": tree.root,
+                    "leaves": tree.leaves(),
+                    "children": tree.children,
+                    "messages": tree.messages,
+                }))),
+                Err(e) => responses.push(error_message(&e.to_string())),
+            }
+        }
+        Some("set_head") => {
+            let chat_id = command["chat_id"].as_str().unwrap_or_default();
+            let message_id = command["message_id"].as_str().unwrap_or_default();
+            match state
+                .set_head(chat_id, message_id)
+                .and_then(|_| state.list_chats(include_archived))
+            {
+                Ok(chats) => responses.push(text_message(json!({
+                    "type": "chats_update",
+                    "status": "success",
+                    "chats": chats,
+                }))),
+                Err(e) => responses.push(error_message(&e.to_string())),
+            }
+        }
+        Some("fork_chat") => {
+            let message_id = command["message_id"].as_str().unwrap_or_default();
+            let source_chat = command["chat_id"].as_str();
+            let title = command["title"].as_str().unwrap_or("Fork");
+            let result = state
+                .fork_chat(title, message_id, source_chat)
+                .and_then(|chat| Ok((chat, state.list_chats(include_archived)?)));
+            match result {
+                Ok((chat, chats)) => responses.push(text_message(json!({
+                    "type": "chats_update",
+                    "status": "success",
+                    "chat_id": chat.id,
+                    "chats": chats,
+                    "messages": state.get_message_chain(chat.head.clone()),
+                }))),
+                Err(e) => responses.push(error_message(&e.to_string())),
+            }
+        }
+        // The commands other actors use work here too, answered with a single frame
+        _ => {
+            let reply = message_server::handle_actor_command(state, command);
+            if reply["status"] == "error" {
+                log(&format!(
+                    "Error handling command {}: {}",
+                    command, reply["message"]
+                ));
+            }
+            responses.push(text_message(reply));
+        }
+    }
+}
+
+// Pass a command to the chat's child actor and answer at once. The host can
+// only reply to the socket that sent the command, so clients poll the chat
+// with `get_history` until the reply lands.
+fn queue_in_chat_actor(
+    state: &mut State,
+    chat_id: &str,
+    command: &Value,
+    responses: &mut Vec<WebsocketMessage>,
+) {
+    let head = state.load_chat(chat_id).map(|chat| chat.head);
+    match head.and_then(|head| Ok((head, state.send_to_chat_actor(chat_id, command)?))) {
+        Ok((head, ())) => responses.push(text_message(json!({
+            "type": "message_queued",
+            "status": "success",
+            "chat_id": chat_id,
+            "head": head,
+        }))),
+        Err(e) => {
+            log(&format!(
+                "Error queueing command for chat {}: {}",
+                chat_id, e
+            ));
+            responses.push(error_message(&e.to_string()));
+        }
+    }
+}
+
+fn send_message(
+    state: &mut State,
+    chat_id: &str,
+    content: &str,
+    parent_id: Option<&str>,
+    attachments: Vec<Attachment>,
+    responses: &mut Vec<WebsocketMessage>,
+) -> Result<Vec<Message>, Box<dyn std::error::Error>> {
+    if content.trim().is_empty() && attachments.is_empty() {
+        return Err("Message is empty".into());
+    }
+    let mut chat = state.load_chat(chat_id)?;
+
+    // An explicit parent branches off an earlier message of this chat instead of the head
+    let parent = match parent_id {
+        Some(id) if !state.chat_contains(chat_id, id)? => {
+            return Err(format!("Message {} is not part of chat {}", id, chat_id).into());
+        }
+        Some(id) => Some(id.to_string()),
+        None => chat.head.clone(),
+    };
+    // A title the user chose is kept
+    let needs_title = parent.is_none() && chat.title == DEFAULT_TITLE;
+
+    // Long chats may first fold their older turns into a summary to continue from
+    let parent = match state.roll_summary(&chat, parent.as_deref())? {
+        Some(summary) => {
+            responses.push(text_message(json!({
+                "type": "message_update",
+                "status": "success",
+                "chat_id": chat_id,
+                "messages": [summary],
+            })));
+            summary.id
+        }
+        None => parent,
+    };
+
+    let mut user_message = Message {
+        role: "user".to_string(),
+        content: content.to_string(),
+        parent,
+        attachments,
+        ..Default::default()
+    };
+    user_message.id = Some(state.save_message(chat_id, &user_message)?);
+
+    responses.push(text_message(json!({
+        "type": "message_update",
+        "status": "success",
+        "chat_id": chat_id,
+        "messages": [user_message],
+    })));
+
+    let replies = generate_reply(state, &mut chat, user_message.id.clone(), responses)?;
+
+    // A failed title is not worth failing the message over
+    if needs_title {
+        let mut exchange = vec![user_message.clone()];
+        exchange.extend(
+            replies
+                .iter()
+                .rev()
+                .find(|m| m.role == "assistant")
+                .cloned(),
+        );
+        if let Err(e) = generate_title(state, &chat, &exchange, responses) {
+            log(&format!("Error generating title for {}: {}", chat.id, e));
+        }
+    }
+
+    let mut messages = vec![user_message];
+    messages.extend(replies);
+    Ok(messages)
+}
+
+// Replace an assistant reply with a freshly generated sibling under the same parent
+fn regenerate(
+    state: &mut State,
+    chat_id: &str,
+    message_id: &str,
+    responses: &mut Vec<WebsocketMessage>,
+) -> Result<Vec<Message>, Box<dyn std::error::Error>> {
+    let mut chat = state.load_chat(chat_id)?;
+    if !state.chat_contains(chat_id, message_id)? {
+        return Err(format!("Message {} is not part of chat {}", message_id, chat_id).into());
+    }
+
+    let message = state.load_message(message_id)?;
+    if message.role != "assistant" {
+        return Err(format!("Message {} is not an assistant reply", message_id).into());
+    }
+
+    generate_reply(state, &mut chat, message.parent, responses)
+}
+
+// Complete the chain ending at `parent` and store the reply. While the reply
+// asks for tools, run them, store each call's output as a `tool` message and
+// complete again, up to `MAX_TOOL_ROUNDS`. Returns every message stored in
+// order. The chat head follows each step so a failed round keeps what came
+// before.
+fn generate_reply(
+    state: &mut State,
+    chat: &mut Chat,
+    parent: Option<String>,
+    responses: &mut Vec<WebsocketMessage>,
+) -> Result<Vec<Message>, Box<dyn std::error::Error>> {
+    let tools = state.chat_tools(chat);
+    let definitions: Vec<ToolDefinition> =
+        tools.iter().map(|tool| tool.definition.clone()).collect();
+    let mut parent = parent;
+    let mut stored = Vec::new();
+
+    for round in 0.. {
+        let chain = state.get_message_chain(parent.clone());
+        let mut context = state.fit_context(chat, chain)?;
+
+        // A summary costs a completion, so each reply stores at most one; later
+        // rounds and messages continue from it like from a rolling summary
+        if round == 0 && !context.unsummarized.is_empty() {
+            let summary = state.store_summary(chat, parent.as_deref(), &context.unsummarized)?;
+            responses.push(text_message(json!({
+                "type": "message_update",
+                "status": "success",
+                "chat_id": chat.id,
+                "messages": [summary],
+            })));
+            parent = summary.id;
+            let truncation = context.truncation;
+            context = state.fit_context(chat, state.get_message_chain(parent.clone()))?;
+            context.truncation = truncation;
+        }
+
+        if let Some(truncation) = &context.truncation {
+            log(&format!(
+                "Truncated {} messages from chat {} ({} of {} tokens)",
+                truncation.dropped, chat.id, truncation.estimated_tokens, truncation.budget
+            ));
+            responses.push(text_message(json!({
+                "type": "context_truncated",
+                "status": "success",
+                "chat_id": chat.id,
+                "truncation": truncation,
+            })));
+        }
+        let completion = state.complete(chat, &context, &definitions)?;
+        state.sync_clock(completion.timestamp);
+
+        // The host buffers the full event stream, so the deltas are relayed as
+        // individual frames ahead of the final message write
+        for delta in &completion.deltas {
+            responses.push(text_message(json!({
+                "type": "message_delta",
+                "status": "success",
+                "chat_id": chat.id,
+                "parent": parent,
+                "delta": delta,
+            })));
+        }
+
+        let mut assistant_message = Message {
+            role: "assistant".to_string(),
+            content: completion.content(),
+            parent,
+            usage: Some(completion.usage),
+            tool_calls: completion.tool_calls,
+            ..Default::default()
+        };
+        assistant_message.id = Some(state.save_message(&chat.id, &assistant_message)?);
+        let mut step = vec![assistant_message.clone()];
+
+        // Every call gets a result, even past the round limit, so the chain stays
+        // valid for the provider when the user carries on
+        for call in &assistant_message.tool_calls {
+            let previous = step.last().and_then(|message| message.id.clone());
+            step.push(state.run_tool(&chat.id, &tools, call, previous)?);
+        }
+        parent = step.last().and_then(|message| message.id.clone());
+
+        // Only the head moves, so edits made to the chat meanwhile are kept
+        let (head, now) = (parent.clone(), state.clock);
+        *chat = state.update_chat(&chat.id, |chat| {
+            chat.head = head;
+            chat.updated_at = now;
+        })?;
+
+        responses.push(text_message(json!({
+            "type": "message_update",
+            "status": "success",
+            "chat_id": chat.id,
+            "messages": step,
+        })));
+        stored.extend(step);
+
+        if assistant_message.tool_calls.is_empty() {
+            break;
+        }
+        if round + 1 >= MAX_TOOL_ROUNDS {
+            log(&format!(
+                "Stopped chat {} after {} tool rounds",
+                chat.id, MAX_TOOL_ROUNDS
+            ));
+            break;
+        }
+    }
+
+    Ok(stored)
+}
+
+// Summarize the opening exchange into a short title with the provider's
+// cheap model. The host can only answer the socket that sent the command,
+// so `chat_updated` reaches that client rather than every connected one.
+fn generate_title(
+    state: &State,
+    chat: &Chat,
+    exchange: &[Message],
+    responses: &mut Vec<WebsocketMessage>,
+) -> Result<Chat, Box<dyn std::error::Error>> {
+    let name = chat.provider.as_deref().unwrap_or(&state.default_provider);
+    let settings = ChatSettings {
+        model: state.provider_config(name)?.title_model().map(String::from),
+        max_tokens: Some(TITLE_MAX_TOKENS),
+        ..ChatSettings::default()
+    };
+
+    let mut prompt = String::from(
+        "Write a title of at most six words for a conversation that opens like this. \
+         Reply with the title only.",
+    );
+    for message in exchange {
+        let excerpt: String = message.content.chars().take(TITLE_EXCERPT_CHARS).collect();
+        prompt.push_str(&format!("\n\n{}: {}", message.role, excerpt));
+    }
+    let request = Message {
+        role: "user".to_string(),
+        content: prompt,
+        ..Default::default()
+    };
+
+    let completion = state.complete_with(Some(&chat.id), name, &[request], &settings, None, &[])?;
+    let title = clean_title(&completion.content()).ok_or("Provider returned an empty title")?;
+    let chat = state.rename_chat(&chat.id, &title)?;
+
+    responses.push(text_message(json!({
+        "type": "chat_updated",
+        "status": "success",
+        "chat": chat,
+    })));
+    Ok(chat)
+}
+
+// Models like to wrap titles in quotes or add a trailing period
+fn clean_title(raw: &str) -> Option<String> {
+    let title = raw
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())?
+        .trim_start_matches("Title:")
+        .trim()
+        .trim_matches(|c| c == '"' || c == '\'' || c == '*')
+        .trim_end_matches('.')
+        .trim();
+    let title: String = title.chars().take(TITLE_MAX_CHARS).collect();
+    (!title.is_empty()).then_some(title)
+}
+
+fn set_provider(
+    state: &State,
+    chat_id: &str,
+    provider: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    state.provider_config(provider)?;
+    let mut chat = state.load_chat(chat_id)?;
+    chat.provider = Some(provider.to_string());
+    state.save_chat(&chat)
+}
+
+fn update_settings(
+    state: &State,
+    chat_id: &str,
+    settings: ChatSettings,
+) -> Result<Chat, Box<dyn std::error::Error>> {
+    state.validate_tools(&settings)?;
+    let mut chat = state.load_chat(chat_id)?;
+    chat.settings = settings;
+    state.save_chat(&chat)?;
+    Ok(chat)
+}
+
+// An empty or missing prompt clears it
+fn prompt_value(value: &Value) -> Option<String> {
+    value
+        .as_str()
+        .map(|prompt| prompt.trim().to_string())
+        .filter(|prompt| !prompt.is_empty())
+}
+
+fn set_system_prompt(
+    state: &State,
+    chat_id: &str,
+    prompt: Option<String>,
+) -> Result<Chat, Box<dyn std::error::Error>> {
+    let mut chat = state.load_chat(chat_id)?;
+    chat.system_prompt = prompt;
+    state.save_chat(&chat)?;
+    Ok(chat)
+}
+
+// Other actors drive chats with the JSON commands in `message_server`.
+// `handle_send` runs a command without replying, so failures only reach the log.
+impl MessageServerClientGuest for Component {
+    fn handle_send(msg: Vec<u8>, state: Vec<u8>) -> Vec<u8> {
+        let mut current_state: State = serde_json::from_slice(&state).unwrap();
+        match serde_json::from_slice::<Value>(&msg) {
+            Ok(command) => {
+                current_state.tick(command["timestamp"].as_u64());
+                let reply = message_server::handle_actor_command(&mut current_state, &command);
+                if reply["status"] == "error" {
+                    log(&format!(
+                        "Error handling actor message: {}",
+                        reply["message"]
+                    ));
+                }
+            }
+            Err(e) => log(&format!("Error parsing actor message: {}", e)),
+        }
+        serde_json::to_vec(&current_state).unwrap()
+    }
+
+    fn handle_request(msg: Vec<u8>, state: Vec<u8>) -> (Vec<u8>, Vec<u8>) {
+        let mut current_state: State = serde_json::from_slice(&state).unwrap();
+        let reply = match serde_json::from_slice::<Value>(&msg) {
+            Ok(command) => {
+                current_state.tick(command["timestamp"].as_u64());
+                message_server::handle_actor_command(&mut current_state, &command)
+            }
+            Err(e) => {
+                log(&format!("Error parsing actor request: {}", e));
+                json!({ "status": "error", "message": "Invalid JSON" })
+            }
+        };
+        (
+            serde_json::to_vec(&reply).unwrap(),
+            serde_json::to_vec(&current_state).unwrap(),
+        )
+    }
+}
+
+bindings::export!(Component with_types_in bindings);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tree_index_links_replies_under_known_parents() {
+        let mut tree = TreeIndex::default();
+        assert!(tree.insert("a", None));
+        assert!(tree.insert("b", Some("a")));
+        assert!(tree.insert("c", Some("a")));
+        // A parent outside the chat makes the message a root of its own
+        assert!(tree.insert("d", Some("elsewhere")));
+        assert!(!tree.insert("b", Some("c")));
+
+        assert_eq!(tree.roots, ["a", "d"]);
+        assert_eq!(tree.children["a"], ["b", "c"]);
+        assert_eq!(tree.children.len(), 1);
+        assert!(tree.contains("c"));
+        assert!(!tree.contains("elsewhere"));
+        assert_eq!(tree.ids().count(), 4);
+    }
+
+    #[test]
+    fn titles_lose_labels_quotes_and_periods() {
+        assert_eq!(
+            clean_title("\n  Title: \"Trip planning.\"\nmore").as_deref(),
+            Some("Trip planning")
+        );
+        assert_eq!(
+            clean_title("**Rust lifetimes**").as_deref(),
+            Some("Rust lifetimes")
+        );
+        assert_eq!(clean_title("'...'"), None);
+        assert_eq!(clean_title("  \n "), None);
+        assert_eq!(
+            clean_title(&"x".repeat(100)).unwrap().len(),
+            TITLE_MAX_CHARS
+        );
+    }
+}
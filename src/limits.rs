@@ -0,0 +1,259 @@
+use crate::bindings::ntwk::theater::http_client::send_http;
+use crate::bindings::ntwk::theater::http_types::HttpRequest;
+use crate::bindings::ntwk::theater::runtime::log;
+use crate::dates::format_day;
+use crate::{response_date, State};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fmt;
+
+const RATE_WINDOW_MS: u64 = 60_000;
+
+// Caps on combined input and output tokens and on USD spent, per period
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Budget {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tokens: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cost: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Limits {
+    #[serde(default)]
+    pub daily: Budget,
+    #[serde(default)]
+    pub monthly: Budget,
+    // Completions each client may request in any rolling minute
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requests_per_minute: Option<usize>,
+}
+
+impl Limits {
+    fn is_empty(&self) -> bool {
+        self.daily.tokens.is_none()
+            && self.daily.cost.is_none()
+            && self.monthly.tokens.is_none()
+            && self.monthly.cost.is_none()
+            && self.requests_per_minute.is_none()
+    }
+}
+
+// Why a request was turned away before reaching the provider
+#[derive(Serialize, Debug)]
+pub struct LimitExceeded {
+    pub code: &'static str,
+    pub limit: &'static str,
+    pub used: f64,
+    pub allowed: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after_ms: Option<u64>,
+    pub message: String,
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+impl LimitExceeded {
+    // The error body sent to clients, shared by every interface
+    pub fn to_json(&self) -> Value {
+        let mut body = json!(self);
+        body["type"] = json!("limit_exceeded");
+        body["status"] = json!("error");
+        body
+    }
+}
+
+fn over_budget(
+    limit: &'static str,
+    period: &str,
+    used: f64,
+    allowed: Option<f64>,
+) -> Result<(), LimitExceeded> {
+    match allowed {
+        Some(allowed) if used >= allowed => Err(LimitExceeded {
+            code: "budget_exceeded",
+            limit,
+            used,
+            allowed,
+            retry_after_ms: None,
+            message: format!(
+                "The {} budget of {} is used up ({} so far)",
+                period, allowed, used
+            ),
+        }),
+        _ => Ok(()),
+    }
+}
+
+impl State {
+    // Ask the default provider for the time. Any response carries a Date
+    // header, so a HEAD request is enough; if it fails, the last Date seen
+    // stands.
+    fn refresh_provider_time(&mut self) {
+        let Ok(config) = self.provider_config(&self.default_provider) else {
+            return;
+        };
+        let request = HttpRequest {
+            method: "HEAD".to_string(),
+            uri: config.url().to_string(),
+            headers: vec![],
+            body: None,
+        };
+        match response_date(&send_http(&request)) {
+            Some(time) => self.provider_time = self.provider_time.max(time),
+            None => log("Provider sent no Date header; limits use the last one seen"),
+        }
+    }
+
+    // Ledger days come from provider Date headers too, so the latest one on
+    // record stands in for today until a provider has been reached
+    fn today(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let today = format_day(self.provider_time);
+        let latest = self.load_ledger()?.into_iter().map(|entry| entry.day).max();
+        Ok(latest.filter(|day| *day > today).unwrap_or(today))
+    }
+
+    // Run a check at the time of the last completion, and again at a fresh
+    // provider time before refusing: no completion moves the time on while
+    // requests are refused, so a new day or minute would never arrive
+    fn confirm_refusal(
+        &mut self,
+        check: impl Fn(&State) -> Result<(), LimitExceeded>,
+    ) -> Result<(), LimitExceeded> {
+        if check(self).is_ok() {
+            return Ok(());
+        }
+        self.refresh_provider_time();
+        check(self)
+    }
+
+    // Reject a completion that would exceed a budget or the client's request
+    // rate. Allowed requests count towards the rate limit. Client timestamps
+    // play no part: a far-future one would otherwise open a fresh day.
+    pub fn check_limits(&mut self, client: &str) -> Result<(), LimitExceeded> {
+        if self.limits.is_empty() {
+            return Ok(());
+        }
+        self.confirm_refusal(|state| {
+            state.within_budgets()?;
+            state.within_rate(client)
+        })?;
+        if self.limits.requests_per_minute.is_some() {
+            self.record_request(client);
+        }
+        Ok(())
+    }
+
+    // Budgets alone, for commands a parent actor already counted against the
+    // rate limit
+    pub fn check_budgets(&mut self) -> Result<(), LimitExceeded> {
+        if self.limits.is_empty() {
+            return Ok(());
+        }
+        self.confirm_refusal(State::within_budgets)
+    }
+
+    fn within_budgets(&self) -> Result<(), LimitExceeded> {
+        let (day_tokens, day_cost, month_tokens, month_cost) =
+            self.spending().map_err(|e| LimitExceeded {
+                code: "budget_unavailable",
+                limit: "ledger",
+                used: 0.0,
+                allowed: 0.0,
+                retry_after_ms: None,
+                message: format!("Could not read the usage ledger: {}", e),
+            })?;
+        let limits = &self.limits;
+        over_budget(
+            "daily_tokens",
+            "daily token",
+            day_tokens as f64,
+            limits.daily.tokens.map(|tokens| tokens as f64),
+        )?;
+        over_budget("daily_cost", "daily cost", day_cost, limits.daily.cost)?;
+        over_budget(
+            "monthly_tokens",
+            "monthly token",
+            month_tokens as f64,
+            limits.monthly.tokens.map(|tokens| tokens as f64),
+        )?;
+        over_budget(
+            "monthly_cost",
+            "monthly cost",
+            month_cost,
+            limits.monthly.cost,
+        )
+    }
+
+    fn within_rate(&self, client: &str) -> Result<(), LimitExceeded> {
+        let Some(allowed) = self.limits.requests_per_minute else {
+            return Ok(());
+        };
+        let now = self.provider_time;
+        let recent: Vec<u64> = self
+            .request_log
+            .get(client)
+            .into_iter()
+            .flatten()
+            .copied()
+            .filter(|&at| at + RATE_WINDOW_MS > now)
+            .collect();
+        if recent.len() < allowed {
+            return Ok(());
+        }
+        let oldest = recent.iter().min().copied().unwrap_or(now);
+        Err(LimitExceeded {
+            code: "rate_limited",
+            limit: "requests_per_minute",
+            used: recent.len() as f64,
+            allowed: allowed as f64,
+            retry_after_ms: Some(oldest + RATE_WINDOW_MS - now),
+            message: format!(
+                "Too many requests: {} per minute allowed for client {}",
+                allowed, client
+            ),
+        })
+    }
+
+    // Log an allowed request, dropping entries that have left the window.
+    // Client ids are whatever clients send, so clients with no recent
+    // requests are dropped too, keeping the log from growing without bound.
+    fn record_request(&mut self, client: &str) {
+        let now = self.provider_time;
+        self.request_log.retain(|_, requests| {
+            requests.retain(|&at| at + RATE_WINDOW_MS > now);
+            !requests.is_empty()
+        });
+        self.request_log
+            .entry(client.to_string())
+            .or_default()
+            .push(now);
+    }
+
+    // Tokens and cost so far today and this month
+    fn spending(&self) -> Result<(u64, f64, u64, f64), Box<dyn std::error::Error>> {
+        let today = self.today()?;
+        let month = &today[..7];
+        let (mut day_tokens, mut day_cost, mut month_tokens, mut month_cost) = (0, 0.0, 0, 0.0);
+        for entry in self.load_ledger()? {
+            if !entry.day.starts_with(month) {
+                continue;
+            }
+            let tokens = entry.input_tokens + entry.output_tokens;
+            let cost = self.entry_cost(&entry).unwrap_or(0.0);
+            month_tokens += tokens;
+            month_cost += cost;
+            if entry.day == today {
+                day_tokens += tokens;
+                day_cost += cost;
+            }
+        }
+        Ok((day_tokens, day_cost, month_tokens, month_cost))
+    }
+}
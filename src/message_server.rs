@@ -0,0 +1,128 @@
+use crate::limits::LimitExceeded;
+use crate::{regenerate, send_message, State, DEFAULT_TITLE};
+use serde_json::{json, Value};
+
+// Run a command from another actor and build its reply. Commands use the
+// same `type` names and fields as their WebSocket counterparts and go through
+// the same state operations, but answer with one JSON value instead of a
+// stream of frames. The WebSocket path hands any command it doesn't handle
+// itself to this function.
+pub fn handle_actor_command(state: &mut State, command: &Value) -> Value {
+    match run_command(state, command) {
+        Ok(mut reply) => {
+            reply["status"] = json!("success");
+            reply
+        }
+        Err(e) => match e.downcast_ref::<LimitExceeded>() {
+            Some(limit) => limit.to_json(),
+            None => json!({ "status": "error", "message": e.to_string() }),
+        },
+    }
+}
+
+fn required<'a>(command: &'a Value, field: &str) -> Result<&'a str, Box<dyn std::error::Error>> {
+    command[field]
+        .as_str()
+        .ok_or_else(|| format!("Missing field: {}", field).into())
+}
+
+// Commands a parent queued in this chat's child were already counted against
+// the parent's rate limit, so the child only checks budgets
+fn check_limits(state: &mut State, command: &Value) -> Result<(), LimitExceeded> {
+    if command["queued"] == true {
+        state.check_budgets()
+    } else {
+        state.check_limits(command["client_id"].as_str().unwrap_or("actor"))
+    }
+}
+
+// With chat actors the parent hands replies to the chat's child instead of
+// waiting on the provider; callers poll `get_history` until the head moves
+fn queue_in_chat_actor(
+    state: &mut State,
+    chat_id: &str,
+    command: &Value,
+) -> Result<Value, Box<dyn std::error::Error>> {
+    let head = state.load_chat(chat_id)?.head;
+    state.send_to_chat_actor(chat_id, command)?;
+    Ok(json!({ "type": "queued", "chat_id": chat_id, "head": head, "queued": true }))
+}
+
+fn run_command(state: &mut State, command: &Value) -> Result<Value, Box<dyn std::error::Error>> {
+    match command["type"].as_str() {
+        Some("create_chat") => {
+            let title = command["title"].as_str().unwrap_or(DEFAULT_TITLE);
+            let provider = command["provider"].as_str().map(String::from);
+            let chat = state.create_chat(title, provider)?;
+            Ok(json!({ "type": "chat", "chat": chat }))
+        }
+        Some("list_chats") => {
+            let include_archived = command["include_archived"].as_bool().unwrap_or(false);
+            Ok(json!({ "type": "chats", "chats": state.list_chats(include_archived)? }))
+        }
+        // The chain from the root to the chat head, or to `message_id` for another branch
+        Some("get_history") => {
+            let chat = state.load_chat(required(command, "chat_id")?)?;
+            let head = match command["message_id"].as_str() {
+                Some(id) => Some(state.load_message(id)?.id.unwrap_or_else(|| id.to_string())),
+                None => chat.head.clone(),
+            };
+            Ok(json!({
+                "type": "history",
+                "chat": chat,
+                "messages": state.get_message_chain(head),
+            }))
+        }
+        // Blocks until the reply, and any tool calls before it, are stored,
+        // unless the chat runs in a child actor
+        Some("send_message") => {
+            let chat_id = required(command, "chat_id")?;
+            let content = command["content"].as_str().unwrap_or_default();
+            let parent_id = command["parent_id"].as_str();
+            let attachments = state.resolve_attachments(&command["attachments"])?;
+            check_limits(state, command)?;
+            if state.chat_actors.is_some() && command["queued"] != true {
+                return queue_in_chat_actor(state, chat_id, command);
+            }
+            let messages = send_message(
+                state,
+                chat_id,
+                content,
+                parent_id,
+                attachments,
+                &mut Vec::new(),
+            )?;
+            let reply = messages.iter().rev().find(|m| m.role == "assistant");
+            Ok(json!({
+                "type": "reply",
+                "chat_id": chat_id,
+                "reply": reply,
+                "messages": messages,
+            }))
+        }
+        Some("regenerate") => {
+            let chat_id = required(command, "chat_id")?;
+            let message_id = required(command, "message_id")?;
+            check_limits(state, command)?;
+            if state.chat_actors.is_some() && command["queued"] != true {
+                return queue_in_chat_actor(state, chat_id, command);
+            }
+            let messages = regenerate(state, chat_id, message_id, &mut Vec::new())?;
+            let reply = messages.iter().rev().find(|m| m.role == "assistant");
+            Ok(json!({
+                "type": "reply",
+                "chat_id": chat_id,
+                "reply": reply,
+                "messages": messages,
+            }))
+        }
+        Some("fork_chat") => {
+            let message_id = required(command, "message_id")?;
+            let title = command["title"].as_str().unwrap_or("Fork");
+            let chat = state.fork_chat(title, message_id, command["chat_id"].as_str())?;
+            Ok(json!({ "type": "chat", "chat": chat }))
+        }
+        Some(other) => Err(format!("Unknown command: {}", other).into()),
+        None => Err("Missing command type".into()),
+    }
+}
@@ -0,0 +1,336 @@
+use crate::bindings::ntwk::theater::http_types::{HttpRequest, HttpResponse};
+use crate::bindings::ntwk::theater::runtime::log;
+use crate::providers::Completion;
+use crate::{json_response, request_client, ChatSettings, Message, State};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha1::{Digest, Sha1};
+
+// The subset of an OpenAI chat completion request the gateway understands
+#[derive(Deserialize)]
+struct ChatCompletionRequest {
+    #[serde(default)]
+    model: Option<String>,
+    messages: Vec<ChatCompletionMessage>,
+    #[serde(default)]
+    max_tokens: Option<u32>,
+    #[serde(default)]
+    temperature: Option<f64>,
+    #[serde(default)]
+    top_p: Option<f64>,
+    #[serde(default)]
+    stop: Option<Stop>,
+    #[serde(default)]
+    stream: bool,
+    // Non-standard: record the final user turn and the reply into this chat
+    #[serde(default)]
+    chat_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ChatCompletionMessage {
+    role: String,
+    // Null on assistant turns that only carried tool calls
+    #[serde(default)]
+    content: Option<MessageContent>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Deserialize)]
+struct ContentPart {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    text: String,
+}
+
+impl ChatCompletionMessage {
+    // Text parts are joined; other kinds such as `image_url` aren't supported
+    fn text(&self) -> Result<String, String> {
+        match &self.content {
+            None => Ok(String::new()),
+            Some(MessageContent::Text(text)) => Ok(text.clone()),
+            Some(MessageContent::Parts(parts)) => parts
+                .iter()
+                .map(|part| match part.kind.as_str() {
+                    "text" => Ok(part.text.as_str()),
+                    kind => Err(format!("Unsupported content part type: {}", kind)),
+                })
+                .collect::<Result<Vec<_>, _>>()
+                .map(|texts| texts.join("\n")),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Stop {
+    One(String),
+    Many(Vec<String>),
+}
+
+// Errors use the OpenAI `{ "error": { ... } }` shape so existing clients can surface them
+fn openai_error(status: u16, kind: &str, message: &str) -> HttpResponse {
+    json_response(
+        status,
+        &json!({
+            "error": {
+                "message": message,
+                "type": kind,
+            }
+        }),
+    )
+}
+
+// Providers are advertised as models; requesting one by name routes to it
+pub fn handle_models(state: &State) -> HttpResponse {
+    let models: Vec<Value> = state
+        .providers
+        .keys()
+        .map(|name| {
+            json!({
+                "id": name,
+                "object": "model",
+                "owned_by": "unified-chat",
+            })
+        })
+        .collect();
+    json_response(200, &json!({ "object": "list", "data": models }))
+}
+
+pub fn handle_chat_completions(state: &mut State, req: &HttpRequest) -> HttpResponse {
+    let request: ChatCompletionRequest =
+        match serde_json::from_slice(req.body.as_deref().unwrap_or_default()) {
+            Ok(request) => request,
+            Err(e) => {
+                return openai_error(
+                    400,
+                    "invalid_request_error",
+                    &format!("Invalid request: {}", e),
+                )
+            }
+        };
+
+    // A model naming a configured provider selects it; anything else is passed
+    // through as a model override for the default provider
+    let (provider, model) = match request.model.as_deref() {
+        Some(name) if state.providers.contains_key(name) => (name.to_string(), None),
+        model => (state.default_provider.clone(), model.map(String::from)),
+    };
+
+    let settings = ChatSettings {
+        model: model.clone(),
+        max_tokens: request.max_tokens,
+        temperature: request.temperature,
+        top_p: request.top_p,
+        stop_sequences: match request.stop {
+            Some(Stop::One(stop)) => vec![stop],
+            Some(Stop::Many(stops)) => stops,
+            None => Vec::new(),
+        },
+        ..ChatSettings::default()
+    };
+
+    // `developer` is the newer name for `system`; tool results can't be
+    // replayed without the calls they answer, which the gateway doesn't take
+    if let Some(m) = request.messages.iter().find(|m| {
+        !matches!(
+            m.role.as_str(),
+            "system" | "developer" | "user" | "assistant"
+        )
+    }) {
+        return openai_error(
+            400,
+            "invalid_request_error",
+            &format!("Unsupported message role: {}", m.role),
+        );
+    }
+
+    let texts = match request
+        .messages
+        .iter()
+        .map(|m| m.text())
+        .collect::<Result<Vec<String>, String>>()
+    {
+        Ok(texts) => texts,
+        Err(e) => return openai_error(400, "invalid_request_error", &e),
+    };
+
+    // System and developer messages are folded into the provider's system
+    // prompt, and turns without text are dropped
+    let system: Vec<&str> = request
+        .messages
+        .iter()
+        .zip(&texts)
+        .filter(|(m, _)| m.role == "system" || m.role == "developer")
+        .map(|(_, text)| text.as_str())
+        .collect();
+    let system = (!system.is_empty()).then(|| system.join("\n\n"));
+    let messages: Vec<Message> = request
+        .messages
+        .iter()
+        .zip(&texts)
+        .filter(|(m, text)| (m.role == "user" || m.role == "assistant") && !text.is_empty())
+        .map(|(m, text)| Message {
+            role: m.role.clone(),
+            content: text.clone(),
+            ..Default::default()
+        })
+        .collect();
+
+    if messages.is_empty() {
+        return openai_error(
+            400,
+            "invalid_request_error",
+            "At least one non-system message is required",
+        );
+    }
+
+    if let Err(e) = state.check_limits(&request_client(req, "gateway")) {
+        let kind = match e.code {
+            "rate_limited" => "rate_limit_error",
+            _ => "insufficient_quota",
+        };
+        return openai_error(429, kind, &e.message);
+    }
+
+    let completion = match state.complete_with(
+        None,
+        &provider,
+        &messages,
+        &settings,
+        system.as_deref(),
+        &[],
+    ) {
+        Ok(completion) => completion,
+        Err(e) => return openai_error(502, "api_error", &e.to_string()),
+    };
+    state.sync_clock(completion.timestamp);
+
+    let mut completion_id = None;
+    if let Some(chat_id) = &request.chat_id {
+        match record_exchange(state, chat_id, messages.last(), &completion) {
+            Ok(id) => completion_id = Some(id),
+            Err(e) => log(&format!("Error recording exchange in {}: {}", chat_id, e)),
+        }
+    }
+    let completion_id = format!(
+        "chatcmpl-{}",
+        completion_id.unwrap_or_else(|| content_hash(&completion.content()))
+    );
+    let model = model.unwrap_or(provider);
+
+    if request.stream {
+        stream_response(&completion_id, &model, &completion)
+    } else {
+        json_response(
+            200,
+            &json!({
+                "id": completion_id,
+                "object": "chat.completion",
+                "model": model,
+                "choices": [{
+                    "index": 0,
+                    "message": { "role": "assistant", "content": completion.content() },
+                    "finish_reason": "stop",
+                }],
+                "usage": usage(&completion),
+            }),
+        )
+    }
+}
+
+// Append the final user turn and the reply to a chat
+fn record_exchange(
+    state: &State,
+    chat_id: &str,
+    user: Option<&Message>,
+    completion: &Completion,
+) -> Result<String, Box<dyn std::error::Error>> {
+    // Accept either a chat id or a title, creating a chat with that title if neither matches
+    let chats = state.get_all_chats()?;
+    let existing = chats
+        .iter()
+        .find(|chat| chat.id == chat_id)
+        .or_else(|| chats.iter().find(|chat| chat.title == chat_id))
+        .cloned();
+    let chat = match existing {
+        Some(chat) => chat,
+        None => state.create_chat(chat_id, None)?,
+    };
+
+    let mut parent = chat.head.clone();
+    if let Some(user) = user.filter(|m| m.role == "user") {
+        let user_message = Message {
+            parent: parent.clone(),
+            ..user.clone()
+        };
+        parent = Some(state.save_message(&chat.id, &user_message)?);
+    }
+
+    let assistant_message = Message {
+        role: "assistant".to_string(),
+        content: completion.content(),
+        parent,
+        usage: Some(completion.usage.clone()),
+        ..Default::default()
+    };
+    let id = state.save_message(&chat.id, &assistant_message)?;
+
+    let now = state.clock;
+    state.update_chat(&chat.id, |chat| {
+        chat.head = Some(id.clone());
+        chat.updated_at = now;
+    })?;
+    Ok(id)
+}
+
+// The host returns the body in one piece, so the chunks are framed as an SSE
+// body for clients that asked to stream
+fn stream_response(id: &str, model: &str, completion: &Completion) -> HttpResponse {
+    let mut body = String::new();
+    let mut push = |chunk: Value| body.push_str(&format!("data: {}\n\n", chunk));
+
+    for delta in &completion.deltas {
+        push(json!({
+            "id": id,
+            "object": "chat.completion.chunk",
+            "model": model,
+            "choices": [{ "index": 0, "delta": { "content": delta }, "finish_reason": null }],
+        }));
+    }
+    push(json!({
+        "id": id,
+        "object": "chat.completion.chunk",
+        "model": model,
+        "choices": [{ "index": 0, "delta": {}, "finish_reason": "stop" }],
+        "usage": usage(completion),
+    }));
+    body.push_str("data: [DONE]\n\n");
+
+    HttpResponse {
+        status: 200,
+        headers: vec![("Content-Type".to_string(), "text/event-stream".to_string())],
+        body: Some(body.into_bytes()),
+    }
+}
+
+fn usage(completion: &Completion) -> Value {
+    json!({
+        "prompt_tokens": completion.usage.input_tokens,
+        "completion_tokens": completion.usage.output_tokens,
+        "total_tokens": completion.usage.input_tokens + completion.usage.output_tokens,
+    })
+}
+
+fn content_hash(content: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(content.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
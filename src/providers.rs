@@ -0,0 +1,688 @@
+use crate::attachments::{base64_decode, Attachment, AttachmentKind};
+use crate::bindings::ntwk::theater::http_types::{HttpRequest, HttpResponse};
+use crate::tools::{ToolCall, ToolDefinition};
+use crate::{ChatSettings, Message, TITLE_MODEL};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+const ANTHROPIC_URL: &str = "https://api.anthropic.com/v1/messages";
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+pub const DEFAULT_MAX_TOKENS: u32 = 1024;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Usage {
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct Completion {
+    pub deltas: Vec<String>,
+    pub usage: Usage,
+    // When the provider answered, from its Date header
+    pub timestamp: Option<u64>,
+    pub tool_calls: Vec<ToolCall>,
+}
+
+impl Completion {
+    pub fn content(&self) -> String {
+        self.deltas.concat()
+    }
+}
+
+pub trait Provider {
+    // Providers without tool support ignore the definitions
+    fn build_request(
+        &self,
+        messages: &[Message],
+        settings: &ChatSettings,
+        system: Option<&str>,
+        tools: &[ToolDefinition],
+    ) -> Result<HttpRequest, Box<dyn std::error::Error>>;
+    fn parse_response(
+        &self,
+        response: &HttpResponse,
+    ) -> Result<Completion, Box<dyn std::error::Error>>;
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AnthropicProvider {
+    pub api_key: String,
+    pub model: String,
+    #[serde(default = "default_anthropic_title_model")]
+    pub title_model: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OpenAiProvider {
+    pub base_url: String,
+    #[serde(default)]
+    pub api_key: Option<String>,
+    pub model: String,
+    #[serde(default)]
+    pub title_model: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OllamaProvider {
+    #[serde(default = "default_ollama_url")]
+    pub base_url: String,
+    pub model: String,
+    #[serde(default)]
+    pub title_model: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ProviderConfig {
+    Anthropic(AnthropicProvider),
+    #[serde(rename = "openai")]
+    OpenAi(OpenAiProvider),
+    Ollama(OllamaProvider),
+}
+
+impl ProviderConfig {
+    pub fn provider(&self) -> &dyn Provider {
+        match self {
+            ProviderConfig::Anthropic(provider) => provider,
+            ProviderConfig::OpenAi(provider) => provider,
+            ProviderConfig::Ollama(provider) => provider,
+        }
+    }
+
+    pub fn model(&self) -> &str {
+        match self {
+            ProviderConfig::Anthropic(provider) => &provider.model,
+            ProviderConfig::OpenAi(provider) => &provider.model,
+            ProviderConfig::Ollama(provider) => &provider.model,
+        }
+    }
+
+    // Where completion requests go, also used to read the provider's clock
+    pub fn url(&self) -> &str {
+        match self {
+            ProviderConfig::Anthropic(_) => ANTHROPIC_URL,
+            ProviderConfig::OpenAi(provider) => &provider.base_url,
+            ProviderConfig::Ollama(provider) => &provider.base_url,
+        }
+    }
+
+    // A cheaper model for housekeeping calls such as titling chats, if configured
+    pub fn title_model(&self) -> Option<&str> {
+        match self {
+            ProviderConfig::Anthropic(provider) => provider.title_model.as_deref(),
+            ProviderConfig::OpenAi(provider) => provider.title_model.as_deref(),
+            ProviderConfig::Ollama(provider) => provider.title_model.as_deref(),
+        }
+    }
+}
+
+fn default_ollama_url() -> String {
+    "http://localhost:11434".to_string()
+}
+
+fn default_anthropic_title_model() -> Option<String> {
+    Some(TITLE_MODEL.to_string())
+}
+
+// Providers without tool or attachment support see tool results as plain
+// user text and attachments as a note of their names, in case a chat that
+// used them is switched over
+fn chat_messages(messages: &[Message]) -> Vec<Value> {
+    messages
+        .iter()
+        .map(|msg| match &msg.tool_result {
+            Some(result) => json!({
+                "role": "user",
+                "content": format!("[{} result]\n{}", result.name, msg.content),
+            }),
+            None if !msg.attachments.is_empty() => {
+                let names: Vec<&str> = msg
+                    .attachments
+                    .iter()
+                    .map(|a| a.name.as_deref().unwrap_or(&a.media_type))
+                    .collect();
+                json!({
+                    "role": msg.role,
+                    "content": format!("[Attached: {}]\n{}", names.join(", "), msg.content),
+                })
+            }
+            None => json!({
+                "role": msg.role,
+                "content": msg.content,
+            }),
+        })
+        .collect()
+}
+
+// Images and PDFs go as base64 sources; plain text documents as the text itself
+fn attachment_block(attachment: &Attachment) -> Value {
+    let Some(data) = &attachment.data else {
+        return json!({
+            "type": "text",
+            "text": format!("[Attachment {} is no longer available]", attachment.id),
+        });
+    };
+    let source = if attachment.media_type == "text/plain" {
+        let text = base64_decode(data).unwrap_or_default();
+        json!({
+            "type": "text",
+            "media_type": "text/plain",
+            "data": String::from_utf8_lossy(&text),
+        })
+    } else {
+        json!({
+            "type": "base64",
+            "media_type": attachment.media_type,
+            "data": data,
+        })
+    };
+    let mut block = json!({ "type": attachment.kind, "source": source });
+    if let (AttachmentKind::Document, Some(name)) = (attachment.kind, &attachment.name) {
+        block["title"] = json!(name);
+    }
+    block
+}
+
+// Anthropic takes tool calls and results as content blocks. Results go back as
+// user turns, and consecutive turns from the same side are merged because the
+// API expects roles to alternate.
+fn anthropic_messages(messages: &[Message]) -> Vec<Value> {
+    let mut turns: Vec<Value> = Vec::new();
+    for msg in messages {
+        let (role, blocks) = match &msg.tool_result {
+            Some(result) => (
+                "user",
+                vec![json!({
+                    "type": "tool_result",
+                    "tool_use_id": result.tool_use_id,
+                    "content": msg.content,
+                    "is_error": result.is_error,
+                })],
+            ),
+            None => {
+                let mut blocks: Vec<Value> = msg.attachments.iter().map(attachment_block).collect();
+                if !msg.content.is_empty() || blocks.is_empty() && msg.tool_calls.is_empty() {
+                    blocks.push(json!({ "type": "text", "text": msg.content }));
+                }
+                blocks.extend(msg.tool_calls.iter().map(|call| {
+                    json!({
+                        "type": "tool_use",
+                        "id": call.id,
+                        "name": call.name,
+                        "input": call.input,
+                    })
+                }));
+                (msg.role.as_str(), blocks)
+            }
+        };
+        match turns.last_mut() {
+            Some(turn) if turn["role"] == role => {
+                if let Some(content) = turn["content"].as_array_mut() {
+                    content.extend(blocks);
+                }
+            }
+            _ => turns.push(json!({ "role": role, "content": blocks })),
+        }
+    }
+    turns
+}
+
+// Chat completion APIs without a dedicated field take the prompt as a leading system message
+fn chat_messages_with_system(messages: &[Message], system: Option<&str>) -> Vec<Value> {
+    let mut chat = Vec::new();
+    if let Some(system) = system {
+        chat.push(json!({ "role": "system", "content": system }));
+    }
+    chat.extend(chat_messages(messages));
+    chat
+}
+
+fn response_body(response: &HttpResponse) -> Result<String, Box<dyn std::error::Error>> {
+    let body = String::from_utf8(response.body.clone().unwrap_or_default())?;
+    if response.status != 200 {
+        return Err(format!("Provider returned {}: {}", response.status, body).into());
+    }
+    Ok(body)
+}
+
+// Parse the `data:` payloads of a server-sent event stream into JSON values
+fn parse_sse_events(body: &str) -> Vec<Value> {
+    body.lines()
+        .filter_map(|line| line.strip_prefix("data:"))
+        .filter_map(|data| serde_json::from_str(data.trim()).ok())
+        .collect()
+}
+
+impl Provider for AnthropicProvider {
+    fn build_request(
+        &self,
+        messages: &[Message],
+        settings: &ChatSettings,
+        system: Option<&str>,
+        tools: &[ToolDefinition],
+    ) -> Result<HttpRequest, Box<dyn std::error::Error>> {
+        let mut body = json!({
+            "model": settings.model.as_deref().unwrap_or(&self.model),
+            "max_tokens": settings.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+            "messages": anthropic_messages(messages),
+            "stream": true,
+        });
+        if let Some(system) = system {
+            body["system"] = json!(system);
+        }
+        if !tools.is_empty() {
+            body["tools"] = json!(tools);
+        }
+        if let Some(temperature) = settings.temperature {
+            body["temperature"] = json!(temperature);
+        }
+        if let Some(top_p) = settings.top_p {
+            body["top_p"] = json!(top_p);
+        }
+        if !settings.stop_sequences.is_empty() {
+            body["stop_sequences"] = json!(settings.stop_sequences);
+        }
+
+        Ok(HttpRequest {
+            method: "POST".to_string(),
+            uri: ANTHROPIC_URL.to_string(),
+            headers: vec![
+                ("Content-Type".to_string(), "application/json".to_string()),
+                ("x-api-key".to_string(), self.api_key.clone()),
+                (
+                    "anthropic-version".to_string(),
+                    ANTHROPIC_VERSION.to_string(),
+                ),
+                ("Accept".to_string(), "text/event-stream".to_string()),
+            ],
+            body: Some(serde_json::to_vec(&body)?),
+        })
+    }
+
+    fn parse_response(
+        &self,
+        response: &HttpResponse,
+    ) -> Result<Completion, Box<dyn std::error::Error>> {
+        let body = response_body(response)?;
+
+        let mut completion = Completion::default();
+        // Tool inputs arrive as JSON fragments, keyed by content block index
+        let mut tool_inputs: HashMap<u64, (usize, String)> = HashMap::new();
+        for event in parse_sse_events(&body) {
+            match event["type"].as_str() {
+                Some("message_start") => {
+                    let usage = &event["message"]["usage"];
+                    completion.usage.input_tokens = usage["input_tokens"].as_u64().unwrap_or(0);
+                    completion.usage.output_tokens = usage["output_tokens"].as_u64().unwrap_or(0);
+                }
+                Some("content_block_delta") if event["delta"]["type"] == "text_delta" => {
+                    if let Some(text) = event["delta"]["text"].as_str() {
+                        completion.deltas.push(text.to_string());
+                    }
+                }
+                Some("content_block_start") if event["content_block"]["type"] == "tool_use" => {
+                    let block = &event["content_block"];
+                    let index = event["index"].as_u64().unwrap_or_default();
+                    tool_inputs.insert(index, (completion.tool_calls.len(), String::new()));
+                    completion.tool_calls.push(ToolCall {
+                        id: block["id"].as_str().unwrap_or_default().to_string(),
+                        name: block["name"].as_str().unwrap_or_default().to_string(),
+                        input: json!({}),
+                    });
+                }
+                Some("content_block_delta") if event["delta"]["type"] == "input_json_delta" => {
+                    let index = event["index"].as_u64().unwrap_or_default();
+                    if let (Some((_, input)), Some(fragment)) = (
+                        tool_inputs.get_mut(&index),
+                        event["delta"]["partial_json"].as_str(),
+                    ) {
+                        input.push_str(fragment);
+                    }
+                }
+                Some("content_block_stop") => {
+                    let index = event["index"].as_u64().unwrap_or_default();
+                    if let Some((position, input)) = tool_inputs.remove(&index) {
+                        if !input.trim().is_empty() {
+                            completion.tool_calls[position].input = serde_json::from_str(&input)?;
+                        }
+                    }
+                }
+                Some("message_delta") => {
+                    if let Some(output_tokens) = event["usage"]["output_tokens"].as_u64() {
+                        completion.usage.output_tokens = output_tokens;
+                    }
+                }
+                Some("error") => {
+                    let message = event["error"]["message"]
+                        .as_str()
+                        .unwrap_or("unknown error");
+                    return Err(format!("Anthropic stream error: {}", message).into());
+                }
+                _ => {}
+            }
+        }
+
+        Ok(completion)
+    }
+}
+
+impl Provider for OpenAiProvider {
+    fn build_request(
+        &self,
+        messages: &[Message],
+        settings: &ChatSettings,
+        system: Option<&str>,
+        _tools: &[ToolDefinition],
+    ) -> Result<HttpRequest, Box<dyn std::error::Error>> {
+        let mut body = json!({
+            "model": settings.model.as_deref().unwrap_or(&self.model),
+            "max_tokens": settings.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+            "messages": chat_messages_with_system(messages, system),
+            "stream": true,
+            "stream_options": { "include_usage": true },
+        });
+        if let Some(temperature) = settings.temperature {
+            body["temperature"] = json!(temperature);
+        }
+        if let Some(top_p) = settings.top_p {
+            body["top_p"] = json!(top_p);
+        }
+        if !settings.stop_sequences.is_empty() {
+            body["stop"] = json!(settings.stop_sequences);
+        }
+
+        let mut headers = vec![
+            ("Content-Type".to_string(), "application/json".to_string()),
+            ("Accept".to_string(), "text/event-stream".to_string()),
+        ];
+        if let Some(api_key) = &self.api_key {
+            headers.push(("Authorization".to_string(), format!("Bearer {}", api_key)));
+        }
+
+        Ok(HttpRequest {
+            method: "POST".to_string(),
+            uri: format!(
+                "{}/v1/chat/completions",
+                self.base_url.trim_end_matches('/')
+            ),
+            headers,
+            body: Some(serde_json::to_vec(&body)?),
+        })
+    }
+
+    fn parse_response(
+        &self,
+        response: &HttpResponse,
+    ) -> Result<Completion, Box<dyn std::error::Error>> {
+        let body = response_body(response)?;
+
+        // The terminating `data: [DONE]` line is not JSON and is skipped by the parser
+        let mut completion = Completion::default();
+        for event in parse_sse_events(&body) {
+            if let Some(message) = event["error"]["message"].as_str() {
+                return Err(format!("OpenAI stream error: {}", message).into());
+            }
+            if let Some(text) = event["choices"][0]["delta"]["content"].as_str() {
+                completion.deltas.push(text.to_string());
+            }
+            if let Some(usage) = event.get("usage").filter(|usage| !usage.is_null()) {
+                completion.usage.input_tokens = usage["prompt_tokens"].as_u64().unwrap_or(0);
+                completion.usage.output_tokens = usage["completion_tokens"].as_u64().unwrap_or(0);
+            }
+        }
+
+        Ok(completion)
+    }
+}
+
+impl Provider for OllamaProvider {
+    fn build_request(
+        &self,
+        messages: &[Message],
+        settings: &ChatSettings,
+        system: Option<&str>,
+        _tools: &[ToolDefinition],
+    ) -> Result<HttpRequest, Box<dyn std::error::Error>> {
+        // Ollama takes sampling parameters in a nested options object
+        let mut options = json!({});
+        if let Some(max_tokens) = settings.max_tokens {
+            options["num_predict"] = json!(max_tokens);
+        }
+        if let Some(temperature) = settings.temperature {
+            options["temperature"] = json!(temperature);
+        }
+        if let Some(top_p) = settings.top_p {
+            options["top_p"] = json!(top_p);
+        }
+        if !settings.stop_sequences.is_empty() {
+            options["stop"] = json!(settings.stop_sequences);
+        }
+
+        Ok(HttpRequest {
+            method: "POST".to_string(),
+            uri: format!("{}/api/chat", self.base_url.trim_end_matches('/')),
+            headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+            body: Some(serde_json::to_vec(&json!({
+                "model": settings.model.as_deref().unwrap_or(&self.model),
+                "messages": chat_messages_with_system(messages, system),
+                "options": options,
+                "stream": true,
+            }))?),
+        })
+    }
+
+    fn parse_response(
+        &self,
+        response: &HttpResponse,
+    ) -> Result<Completion, Box<dyn std::error::Error>> {
+        let body = response_body(response)?;
+
+        // Ollama streams newline-delimited JSON objects rather than SSE
+        let mut completion = Completion::default();
+        for line in body.lines().filter(|line| !line.trim().is_empty()) {
+            let chunk: Value = serde_json::from_str(line)?;
+            if let Some(message) = chunk["error"].as_str() {
+                return Err(format!("Ollama error: {}", message).into());
+            }
+            if let Some(text) = chunk["message"]["content"].as_str() {
+                if !text.is_empty() {
+                    completion.deltas.push(text.to_string());
+                }
+            }
+            if chunk["done"].as_bool() == Some(true) {
+                completion.usage.input_tokens = chunk["prompt_eval_count"].as_u64().unwrap_or(0);
+                completion.usage.output_tokens = chunk["eval_count"].as_u64().unwrap_or(0);
+            }
+        }
+
+        Ok(completion)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::ToolResult;
+
+    fn message(role: &str, content: &str) -> Message {
+        Message {
+            role: role.to_string(),
+            content: content.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn tool_result(call: &str, output: &str) -> Message {
+        Message {
+            tool_result: Some(ToolResult {
+                tool_use_id: call.to_string(),
+                name: "read_file".to_string(),
+                is_error: false,
+            }),
+            ..message("tool", output)
+        }
+    }
+
+    fn response(body: &str) -> HttpResponse {
+        HttpResponse {
+            status: 200,
+            headers: vec![],
+            body: Some(body.as_bytes().to_vec()),
+        }
+    }
+
+    fn anthropic() -> AnthropicProvider {
+        AnthropicProvider {
+            api_key: "key".to_string(),
+            model: "model".to_string(),
+            title_model: None,
+        }
+    }
+
+    #[test]
+    fn tool_results_merge_into_one_user_turn() {
+        let mut call = message("assistant", "Reading both");
+        call.tool_calls = ["a", "b"]
+            .iter()
+            .map(|id| ToolCall {
+                id: id.to_string(),
+                name: "read_file".to_string(),
+                input: json!({ "path": "notes.txt" }),
+            })
+            .collect();
+        let turns = anthropic_messages(&[
+            message("user", "Read my notes"),
+            call,
+            tool_result("a", "first"),
+            tool_result("b", "second"),
+            message("user", "Thanks"),
+        ]);
+
+        let roles: Vec<&str> = turns
+            .iter()
+            .map(|turn| turn["role"].as_str().unwrap())
+            .collect();
+        assert_eq!(roles, ["user", "assistant", "user"]);
+        let assistant = turns[1]["content"].as_array().unwrap();
+        assert_eq!(assistant.len(), 3);
+        assert_eq!(assistant[0]["type"], "text");
+        assert_eq!(assistant[2]["id"], "b");
+        let results = turns[2]["content"].as_array().unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0]["tool_use_id"], "a");
+        assert_eq!(results[1]["content"], "second");
+        assert_eq!(results[2]["text"], "Thanks");
+    }
+
+    #[test]
+    fn empty_tool_call_messages_have_no_text_block() {
+        let mut call = message("assistant", "");
+        call.tool_calls = vec![ToolCall {
+            id: "a".to_string(),
+            name: "list_files".to_string(),
+            input: json!({}),
+        }];
+        let turns = anthropic_messages(&[call, message("assistant", "")]);
+
+        let blocks = turns[0]["content"].as_array().unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0]["type"], "tool_use");
+        assert_eq!(blocks[1], json!({ "type": "text", "text": "" }));
+    }
+
+    #[test]
+    fn sse_parsing_keeps_only_json_data_lines() {
+        let events = parse_sse_events(
+            "event: ping\ndata: {\"a\":1}\n\n: comment\ndata:{\"b\":2}\ndata: [DONE]\n",
+        );
+        assert_eq!(events, [json!({ "a": 1 }), json!({ "b": 2 })]);
+    }
+
+    #[test]
+    fn anthropic_streams_collect_text_tools_and_usage() {
+        let body = [
+            r#"{"type":"message_start","message":{"usage":{"input_tokens":12,"output_tokens":1}}}"#,
+            r#"{"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Hel"}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"lo"}}"#,
+            r#"{"type":"content_block_stop","index":0}"#,
+            r#"{"type":"content_block_start","index":1,"content_block":{"type":"tool_use","id":"call","name":"read_file"}}"#,
+            r#"{"type":"content_block_delta","index":1,"delta":{"type":"input_json_delta","partial_json":"{\"path\":"}}"#,
+            r#"{"type":"content_block_delta","index":1,"delta":{"type":"input_json_delta","partial_json":"\"notes.txt\"}"}}"#,
+            r#"{"type":"content_block_stop","index":1}"#,
+            r#"{"type":"message_delta","usage":{"output_tokens":30}}"#,
+        ]
+        .iter()
+        .map(|event| format!("event: x\ndata: {}\n\n", event))
+        .collect::<String>();
+
+        let completion = anthropic().parse_response(&response(&body)).unwrap();
+        assert_eq!(completion.content(), "Hello");
+        assert_eq!(completion.usage.input_tokens, 12);
+        assert_eq!(completion.usage.output_tokens, 30);
+        assert_eq!(completion.tool_calls.len(), 1);
+        assert_eq!(completion.tool_calls[0].id, "call");
+        assert_eq!(
+            completion.tool_calls[0].input,
+            json!({ "path": "notes.txt" })
+        );
+    }
+
+    #[test]
+    fn anthropic_stream_errors_fail_the_completion() {
+        let body =
+            r#"data: {"type":"error","error":{"type":"overloaded_error","message":"Overloaded"}}"#;
+        let error = anthropic().parse_response(&response(body)).unwrap_err();
+        assert_eq!(error.to_string(), "Anthropic stream error: Overloaded");
+    }
+
+    #[test]
+    fn openai_streams_collect_deltas_and_usage() {
+        let provider = OpenAiProvider {
+            base_url: "http://localhost".to_string(),
+            api_key: None,
+            model: "model".to_string(),
+            title_model: None,
+        };
+        let body = concat!(
+            "data: {\"choices\":[{\"delta\":{\"role\":\"assistant\"}}],\"usage\":null}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"Hi\"}}],\"usage\":null}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\" there\"}}],\"usage\":null}\n\n",
+            "data: {\"choices\":[],\"usage\":{\"prompt_tokens\":5,\"completion_tokens\":2}}\n\n",
+            "data: [DONE]\n\n",
+        );
+
+        let completion = provider.parse_response(&response(body)).unwrap();
+        assert_eq!(completion.deltas, ["Hi", " there"]);
+        assert_eq!(completion.usage.input_tokens, 5);
+        assert_eq!(completion.usage.output_tokens, 2);
+    }
+
+    #[test]
+    fn ollama_streams_are_newline_delimited_json() {
+        let provider = OllamaProvider {
+            base_url: default_ollama_url(),
+            model: "model".to_string(),
+            title_model: None,
+        };
+        let body = concat!(
+            "{\"message\":{\"role\":\"assistant\",\"content\":\"Hi\"},\"done\":false}\n",
+            "\n",
+            "{\"message\":{\"role\":\"assistant\",\"content\":\" there\"},\"done\":false}\n",
+            "{\"message\":{\"role\":\"assistant\",\"content\":\"\"},\"done\":true,",
+            "\"prompt_eval_count\":7,\"eval_count\":3}\n",
+        );
+
+        let completion = provider.parse_response(&response(body)).unwrap();
+        assert_eq!(completion.deltas, ["Hi", " there"]);
+        assert_eq!(completion.usage.input_tokens, 7);
+        assert_eq!(completion.usage.output_tokens, 3);
+
+        let error = provider
+            .parse_response(&response("{\"error\":\"model not found\"}\n"))
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Ollama error: model not found");
+    }
+}
@@ -0,0 +1,210 @@
+use crate::bindings::ntwk::theater::filesystem::{create_dir, path_exists, read_file, write_file};
+use crate::bindings::ntwk::theater::runtime::log;
+use crate::State;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+
+// Characters of context kept on either side of the first match in a snippet
+const SNIPPET_BEFORE: usize = 60;
+const SNIPPET_AFTER: usize = 120;
+
+// Maps each lowercased term to the ids of the messages containing it
+type SearchIndex = HashMap<String, Vec<String>>;
+
+#[derive(Serialize, Debug)]
+pub struct SearchHit {
+    pub message_id: String,
+    pub role: String,
+    pub chat_ids: Vec<String>,
+    pub snippet: String,
+    // Character ranges within the snippet that matched a query term
+    pub highlights: Vec<Range<usize>>,
+}
+
+// Split text into lowercased alphanumeric terms with their character ranges
+fn tokenize(text: &str) -> Vec<(String, Range<usize>)> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut start = 0;
+    for (i, c) in text.chars().enumerate() {
+        if c.is_alphanumeric() {
+            if current.is_empty() {
+                start = i;
+            }
+            current.extend(c.to_lowercase());
+        } else if !current.is_empty() {
+            tokens.push((std::mem::take(&mut current), start..i));
+        }
+    }
+    if !current.is_empty() {
+        tokens.push((current, start..text.chars().count()));
+    }
+    tokens
+}
+
+fn terms(text: &str) -> HashSet<String> {
+    tokenize(text).into_iter().map(|(term, _)| term).collect()
+}
+
+// Cut a window around the first match and report where the matches fall in it
+fn snippet(content: &str, query: &HashSet<String>) -> (String, Vec<Range<usize>>) {
+    let matches: Vec<Range<usize>> = tokenize(content)
+        .into_iter()
+        .filter(|(term, _)| query.contains(term))
+        .map(|(_, range)| range)
+        .collect();
+
+    let first = matches.first().map(|range| range.start).unwrap_or(0);
+    let start = first.saturating_sub(SNIPPET_BEFORE);
+    let end = first + SNIPPET_AFTER;
+    let snippet = content.chars().skip(start).take(end - start).collect();
+    let highlights = matches
+        .into_iter()
+        .filter(|range| range.end <= end)
+        .map(|range| range.start - start..range.end - start)
+        .collect();
+    (snippet, highlights)
+}
+
+impl State {
+    fn search_indexes_path(&self) -> String {
+        format!("{}/search", self.chat_path())
+    }
+
+    // Each chat indexes its own messages, so child actors never rewrite one
+    // shared file, and a hit's chats are the indexes it was found in
+    pub fn search_index_path(&self, chat_id: &str) -> String {
+        format!("{}/{}.json", self.search_indexes_path(), chat_id)
+    }
+
+    fn load_search_index(&self, chat_id: &str) -> Result<SearchIndex, Box<dyn std::error::Error>> {
+        if !path_exists(&self.search_index_path(chat_id))? {
+            return Ok(SearchIndex::new());
+        }
+        let content = read_file(&self.search_index_path(chat_id))?;
+        Ok(serde_json::from_slice(&content)?)
+    }
+
+    // Rebuild every chat's term index from the messages in its tree
+    pub fn rebuild_search_index(&self) -> Result<usize, Box<dyn std::error::Error>> {
+        if !path_exists(&self.search_indexes_path())? {
+            create_dir(&self.search_indexes_path())?;
+        }
+        let mut indexed = HashSet::new();
+        for chat in self.get_all_chats()? {
+            let mut index = SearchIndex::new();
+            for message_id in self.load_tree_index(&chat.id)?.ids() {
+                match self.load_message(message_id) {
+                    Ok(message) => {
+                        for term in terms(&message.content) {
+                            index.entry(term).or_default().push(message_id.clone());
+                        }
+                        indexed.insert(message_id.clone());
+                    }
+                    Err(e) => log(&format!("Error loading message {}: {}", message_id, e)),
+                }
+            }
+            write_file(
+                &self.search_index_path(&chat.id),
+                &serde_json::to_string(&index)?,
+            )?;
+        }
+        Ok(indexed.len())
+    }
+
+    pub fn index_message_terms(
+        &self,
+        chat_id: &str,
+        message_id: &str,
+        content: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut index = self.load_search_index(chat_id)?;
+        let mut changed = false;
+        for term in terms(content) {
+            let ids = index.entry(term).or_default();
+            if !ids.iter().any(|id| id == message_id) {
+                ids.push(message_id.to_string());
+                changed = true;
+            }
+        }
+        if changed {
+            write_file(
+                &self.search_index_path(chat_id),
+                &serde_json::to_string(&index)?,
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn search_index_exists(&self) -> bool {
+        path_exists(&self.search_indexes_path()).unwrap_or(false)
+    }
+
+    // Messages containing every term of the query, most matches first
+    pub fn search(
+        &self,
+        query: &str,
+        limit: usize,
+    ) -> Result<Vec<SearchHit>, Box<dyn std::error::Error>> {
+        let query = terms(query);
+        if query.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // Match within each chat's index, noting which chats every hit is in.
+        // A chat whose index can't be read is left out.
+        let mut chats_by_message: HashMap<String, Vec<String>> = HashMap::new();
+        for chat in self.get_all_chats()? {
+            let index = match self.load_search_index(&chat.id) {
+                Ok(index) => index,
+                Err(e) => {
+                    log(&format!(
+                        "Error loading search index of chat {}: {}",
+                        chat.id, e
+                    ));
+                    continue;
+                }
+            };
+            let mut candidates: Option<HashSet<&String>> = None;
+            for term in &query {
+                let ids: HashSet<&String> = index.get(term).into_iter().flatten().collect();
+                candidates = Some(match candidates {
+                    Some(found) => found.intersection(&ids).cloned().collect(),
+                    None => ids,
+                });
+            }
+            for id in candidates.unwrap_or_default() {
+                chats_by_message
+                    .entry(id.clone())
+                    .or_default()
+                    .push(chat.id.clone());
+            }
+        }
+
+        let mut hits = Vec::new();
+        for (id, chat_ids) in chats_by_message {
+            // The index can briefly outlive a collected message
+            let message = match self.load_message(&id) {
+                Ok(message) => message,
+                Err(_) => continue,
+            };
+            let (snippet, highlights) = snippet(&message.content, &query);
+            hits.push(SearchHit {
+                message_id: id,
+                role: message.role,
+                chat_ids,
+                snippet,
+                highlights,
+            });
+        }
+        hits.sort_by(|a, b| {
+            b.highlights
+                .len()
+                .cmp(&a.highlights.len())
+                .then(a.message_id.cmp(&b.message_id))
+        });
+        hits.truncate(limit);
+        Ok(hits)
+    }
+}
@@ -0,0 +1,301 @@
+use crate::bindings::ntwk::theater::filesystem::{list_files, path_exists, read_file};
+use crate::bindings::ntwk::theater::message_server_host::request;
+use crate::{Chat, ChatSettings, Message, State};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+// Larger files are cut off so one read can't fill the context window
+const READ_LIMIT_BYTES: usize = 100_000;
+// The built-in tools only see this directory, so credentials, configuration
+// and chat data elsewhere in the filesystem handler's directory stay out of reach
+pub const TOOL_ROOT: &str = "workspace";
+
+// What the model sees: a name, what the tool does and a JSON schema for its input
+#[derive(Serialize, Clone, Debug)]
+pub struct ToolDefinition {
+    pub name: String,
+    pub description: String,
+    pub input_schema: Value,
+}
+
+enum Handler {
+    Builtin(fn(&Value) -> Result<String, Box<dyn std::error::Error>>),
+    // Forwarded to another actor through the message server
+    Actor(String),
+}
+
+// A tool the actor can run on the model's behalf
+pub struct Tool {
+    pub definition: ToolDefinition,
+    handler: Handler,
+}
+
+// A tool served by another actor, declared in a chat's settings
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ActorTool {
+    pub name: String,
+    pub description: String,
+    #[serde(default = "default_input_schema")]
+    pub input_schema: Value,
+    pub actor_id: String,
+}
+
+fn default_input_schema() -> Value {
+    json!({ "type": "object" })
+}
+
+// A `tool_use` block from an assistant reply
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    pub input: Value,
+}
+
+// Marks a `tool` message as the answer to one call; the output is the message content
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ToolResult {
+    pub tool_use_id: String,
+    pub name: String,
+    #[serde(default)]
+    pub is_error: bool,
+}
+
+pub fn builtin_tools() -> Vec<Tool> {
+    vec![
+        Tool {
+            definition: ToolDefinition {
+                name: "read_file".to_string(),
+                description: "Read a text file from the actor's sandboxed filesystem. \
+                              Paths are relative to the sandbox root."
+                    .to_string(),
+                input_schema: json!({
+                    "type": "object",
+                    "properties": {
+                        "path": { "type": "string", "description": "Path of the file to read" },
+                    },
+                    "required": ["path"],
+                }),
+            },
+            handler: Handler::Builtin(read_file_tool),
+        },
+        Tool {
+            definition: ToolDefinition {
+                name: "list_files".to_string(),
+                description: "List the entries of a directory in the actor's sandboxed \
+                              filesystem. Paths are relative to the sandbox root."
+                    .to_string(),
+                input_schema: json!({
+                    "type": "object",
+                    "properties": {
+                        "path": {
+                            "type": "string",
+                            "description": "Directory to list; defaults to the sandbox root",
+                        },
+                    },
+                }),
+            },
+            handler: Handler::Builtin(list_files_tool),
+        },
+    ]
+}
+
+// Resolve a model-supplied path inside the tool root, refusing anything that
+// could escape it
+fn sandbox_path(input: &Value) -> Result<String, Box<dyn std::error::Error>> {
+    let path = input["path"].as_str().unwrap_or(".").trim();
+    if path.starts_with('/') || path.contains('\\') {
+        return Err(format!("Path must be relative to the sandbox: {}", path).into());
+    }
+    let parts: Vec<&str> = path
+        .split('/')
+        .filter(|part| !part.is_empty() && *part != ".")
+        .collect();
+    if parts.contains(&"..") {
+        return Err(format!("Path may not leave the sandbox: {}", path).into());
+    }
+    Ok(if parts.is_empty() {
+        TOOL_ROOT.to_string()
+    } else {
+        format!("{}/{}", TOOL_ROOT, parts.join("/"))
+    })
+}
+
+fn read_file_tool(input: &Value) -> Result<String, Box<dyn std::error::Error>> {
+    let path = sandbox_path(input)?;
+    if !path_exists(&path)? {
+        return Err(format!("No such file: {}", path).into());
+    }
+    let content = read_file(&path)?;
+    let mut text =
+        String::from_utf8_lossy(&content[..content.len().min(READ_LIMIT_BYTES)]).into_owned();
+    if content.len() > READ_LIMIT_BYTES {
+        text.push_str(&format!(
+            "\n\n[Truncated: showing {} of {} bytes]",
+            READ_LIMIT_BYTES,
+            content.len()
+        ));
+    }
+    Ok(text)
+}
+
+fn list_files_tool(input: &Value) -> Result<String, Box<dyn std::error::Error>> {
+    let path = sandbox_path(input)?;
+    let mut entries = list_files(&path)?;
+    entries.sort();
+    Ok(entries.join("\n"))
+}
+
+// Send the call to the actor serving the tool and return its JSON reply. A
+// reply with `"status": "error"` is passed to the model as a failed call.
+fn call_actor(actor_id: &str, call: &ToolCall) -> Result<String, Box<dyn std::error::Error>> {
+    let message = json!({
+        "type": "tool_call",
+        "tool": call.name,
+        "input": call.input,
+    });
+    let reply = request(&actor_id.to_string(), &serde_json::to_vec(&message)?)?;
+    let reply: Value = serde_json::from_slice(&reply)
+        .map_err(|e| format!("Actor {} returned invalid JSON: {}", actor_id, e))?;
+    if reply["status"] == "error" {
+        return Err(reply["message"]
+            .as_str()
+            .map_or_else(|| reply.to_string(), String::from)
+            .into());
+    }
+    Ok(reply.to_string())
+}
+
+// Tool names as the Anthropic API accepts them
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+impl State {
+    // The built-in tools a chat has turned on, plus those it binds to other actors
+    pub fn chat_tools(&self, chat: &Chat) -> Vec<Tool> {
+        let mut tools: Vec<Tool> = builtin_tools()
+            .into_iter()
+            .filter(|tool| chat.settings.tools.contains(&tool.definition.name))
+            .collect();
+        tools.extend(chat.settings.actor_tools.iter().map(|tool| Tool {
+            definition: ToolDefinition {
+                name: tool.name.clone(),
+                description: tool.description.clone(),
+                input_schema: tool.input_schema.clone(),
+            },
+            handler: Handler::Actor(tool.actor_id.clone()),
+        }));
+        tools
+    }
+
+    pub fn validate_tools(
+        &self,
+        settings: &ChatSettings,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let builtins = builtin_tools();
+        let is_builtin = |name: &str| builtins.iter().any(|tool| tool.definition.name == name);
+        if let Some(name) = settings.tools.iter().find(|name| !is_builtin(name)) {
+            return Err(format!("Unknown tool: {}", name).into());
+        }
+
+        let mut names: Vec<&str> = Vec::new();
+        for tool in &settings.actor_tools {
+            if !valid_name(&tool.name) {
+                return Err(format!(
+                    "Invalid tool name: {} (use up to 64 letters, digits, _ or -)",
+                    tool.name
+                )
+                .into());
+            }
+            if is_builtin(&tool.name) || names.contains(&tool.name.as_str()) {
+                return Err(format!("Duplicate tool name: {}", tool.name).into());
+            }
+            if tool.actor_id.trim().is_empty() {
+                return Err(format!("Tool {} has no actor_id", tool.name).into());
+            }
+            names.push(&tool.name);
+        }
+        Ok(())
+    }
+
+    // Run one call and store its output as a `tool` message under `parent`.
+    // Failures are reported back to the model rather than ending the reply.
+    pub fn run_tool(
+        &self,
+        chat_id: &str,
+        tools: &[Tool],
+        call: &ToolCall,
+        parent: Option<String>,
+    ) -> Result<Message, Box<dyn std::error::Error>> {
+        let output = match tools.iter().find(|tool| tool.definition.name == call.name) {
+            Some(tool) => match &tool.handler {
+                Handler::Builtin(handler) => handler(&call.input),
+                Handler::Actor(actor_id) => call_actor(actor_id, call),
+            },
+            None => Err(format!("Unknown tool: {}", call.name).into()),
+        };
+        let (content, is_error) = match output {
+            Ok(content) => (content, false),
+            Err(e) => (e.to_string(), true),
+        };
+
+        let mut message = Message {
+            role: "tool".to_string(),
+            content,
+            parent,
+            tool_result: Some(ToolResult {
+                tool_use_id: call.id.clone(),
+                name: call.name.clone(),
+                is_error,
+            }),
+            ..Default::default()
+        };
+        message.id = Some(self.save_message(chat_id, &message)?);
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(path: &str) -> Result<String, String> {
+        sandbox_path(&json!({ "path": path })).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn paths_resolve_inside_the_tool_root() {
+        assert_eq!(resolve("notes.txt").unwrap(), "workspace/notes.txt");
+        assert_eq!(resolve("docs/a/b.md").unwrap(), "workspace/docs/a/b.md");
+        assert_eq!(resolve("./docs//b.md/").unwrap(), "workspace/docs/b.md");
+    }
+
+    #[test]
+    fn empty_paths_mean_the_root() {
+        assert_eq!(resolve("").unwrap(), "workspace");
+        assert_eq!(resolve(".").unwrap(), "workspace");
+        assert_eq!(resolve("./").unwrap(), "workspace");
+        assert_eq!(sandbox_path(&json!({})).unwrap(), "workspace");
+    }
+
+    #[test]
+    fn parent_directories_are_refused() {
+        assert!(resolve("..").is_err());
+        assert!(resolve("../api-key.txt").is_err());
+        assert!(resolve("a/../..").is_err());
+        assert!(resolve("a/../b").is_err());
+    }
+
+    #[test]
+    fn absolute_and_windows_paths_are_refused() {
+        assert!(resolve("/etc/passwd").is_err());
+        assert!(resolve("/workspace/notes.txt").is_err());
+        assert!(resolve("..\\data").is_err());
+        assert!(resolve("docs\\notes.txt").is_err());
+    }
+}
@@ -0,0 +1,266 @@
+use crate::bindings::ntwk::theater::filesystem::{
+    create_dir, list_files, path_exists, read_file, write_file,
+};
+use crate::dates::format_day;
+use crate::providers::Usage;
+use crate::State;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+// USD per million tokens
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Rate {
+    pub input: f64,
+    pub output: f64,
+}
+
+// List prices for the models the actor ships with; `rates.json` can add or override entries
+pub fn default_rates() -> HashMap<String, Rate> {
+    [
+        ("claude-3-5-sonnet", 3.0, 15.0),
+        ("claude-3-5-haiku", 0.8, 4.0),
+        ("claude-3-opus", 15.0, 75.0),
+        ("claude-3-haiku", 0.25, 1.25),
+        ("gpt-4o-mini", 0.15, 0.6),
+        ("gpt-4o", 2.5, 10.0),
+    ]
+    .into_iter()
+    .map(|(model, input, output)| (model.to_string(), Rate { input, output }))
+    .collect()
+}
+
+// Token totals for one model in one chat on one day
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LedgerEntry {
+    pub day: String,
+    // None for gateway requests and other calls made outside a chat
+    #[serde(default)]
+    pub chat_id: Option<String>,
+    pub provider: String,
+    pub model: String,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub requests: u64,
+}
+
+// Filters for `usage_report`; days are inclusive `YYYY-MM-DD` bounds
+pub struct UsageQuery<'a> {
+    pub from: Option<&'a str>,
+    pub to: Option<&'a str>,
+    pub chat_id: Option<&'a str>,
+}
+
+#[derive(Default)]
+struct Totals {
+    input_tokens: u64,
+    output_tokens: u64,
+    requests: u64,
+    cost: f64,
+}
+
+impl Totals {
+    fn add(&mut self, entry: &LedgerEntry, cost: Option<f64>) {
+        self.input_tokens += entry.input_tokens;
+        self.output_tokens += entry.output_tokens;
+        self.requests += entry.requests;
+        self.cost += cost.unwrap_or(0.0);
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "input_tokens": self.input_tokens,
+            "output_tokens": self.output_tokens,
+            "requests": self.requests,
+            "cost": (self.cost * 1e6).round() / 1e6,
+        })
+    }
+}
+
+impl State {
+    fn ledgers_path(&self) -> String {
+        format!("{}/usage", self.chat_path())
+    }
+
+    // Each chat's rows live in a file of their own, written only by whichever
+    // actor runs that chat; rows outside a chat stay in the parent's ledger
+    fn ledger_path(&self, chat_id: Option<&str>) -> String {
+        match chat_id {
+            Some(id) => format!("{}/{}.txt", self.ledgers_path(), id),
+            None => format!("{}/usage-ledger.txt", self.chat_path()),
+        }
+    }
+
+    fn load_ledger_file(&self, path: &str) -> Result<Vec<LedgerEntry>, Box<dyn std::error::Error>> {
+        if !path_exists(path)? {
+            return Ok(Vec::new());
+        }
+        let content = read_file(path)?;
+        Ok(serde_json::from_slice(&content)?)
+    }
+
+    // Every row, including those of deleted chats
+    pub fn load_ledger(&self) -> Result<Vec<LedgerEntry>, Box<dyn std::error::Error>> {
+        let mut ledger = self.load_ledger_file(&self.ledger_path(None))?;
+        if path_exists(&self.ledgers_path())? {
+            for path in list_files(&self.ledgers_path())? {
+                let name = path.rsplit('/').next().unwrap_or_default();
+                ledger.extend(self.load_ledger_file(&format!(
+                    "{}/{}",
+                    self.ledgers_path(),
+                    name
+                ))?);
+            }
+        }
+        Ok(ledger)
+    }
+
+    pub fn ensure_ledgers(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if !path_exists(&self.ledgers_path())? {
+            create_dir(&self.ledgers_path())?;
+        }
+        Ok(())
+    }
+
+    // Add a response's tokens to the ledger row for its day, chat and model
+    pub fn record_usage(
+        &self,
+        timestamp: u64,
+        chat_id: Option<&str>,
+        provider: &str,
+        model: &str,
+        usage: &Usage,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let day = format_day(timestamp);
+        let path = self.ledger_path(chat_id);
+        let mut ledger = self.load_ledger_file(&path)?;
+        let entry = match ledger.iter_mut().find(|entry| {
+            entry.day == day
+                && entry.chat_id.as_deref() == chat_id
+                && entry.provider == provider
+                && entry.model == model
+        }) {
+            Some(entry) => entry,
+            None => {
+                ledger.push(LedgerEntry {
+                    day,
+                    chat_id: chat_id.map(String::from),
+                    provider: provider.to_string(),
+                    model: model.to_string(),
+                    input_tokens: 0,
+                    output_tokens: 0,
+                    requests: 0,
+                });
+                ledger.last_mut().unwrap()
+            }
+        };
+        entry.input_tokens += usage.input_tokens;
+        entry.output_tokens += usage.output_tokens;
+        entry.requests += 1;
+        write_file(&path, &serde_json::to_string(&ledger)?)?;
+        Ok(())
+    }
+
+    // Exact model names win; otherwise the longest configured prefix, so
+    // `claude-3-5-sonnet` prices every dated release of it
+    pub fn rate(&self, model: &str) -> Option<&Rate> {
+        self.rates.get(model).or_else(|| {
+            self.rates
+                .iter()
+                .filter(|(name, _)| model.starts_with(name.as_str()))
+                .max_by_key(|(name, _)| name.len())
+                .map(|(_, rate)| rate)
+        })
+    }
+
+    pub fn entry_cost(&self, entry: &LedgerEntry) -> Option<f64> {
+        self.rate(&entry.model).map(|rate| {
+            (entry.input_tokens as f64 * rate.input + entry.output_tokens as f64 * rate.output)
+                / 1_000_000.0
+        })
+    }
+
+    // Totals plus breakdowns by model, chat and day. Costs only include models
+    // with a rate; the rest are listed under `unpriced_models`.
+    pub fn usage_report(&self, query: &UsageQuery) -> Result<Value, Box<dyn std::error::Error>> {
+        let entries: Vec<LedgerEntry> = self
+            .load_ledger()?
+            .into_iter()
+            .filter(|entry| query.from.is_none_or(|from| entry.day.as_str() >= from))
+            .filter(|entry| query.to.is_none_or(|to| entry.day.as_str() <= to))
+            .filter(|entry| {
+                query
+                    .chat_id
+                    .is_none_or(|id| entry.chat_id.as_deref() == Some(id))
+            })
+            .collect();
+
+        let titles: HashMap<String, String> = self
+            .get_all_chats()?
+            .into_iter()
+            .map(|chat| (chat.id, chat.title))
+            .collect();
+
+        let mut total = Totals::default();
+        let mut by_model: BTreeMap<(String, String), Totals> = BTreeMap::new();
+        let mut by_chat: BTreeMap<Option<String>, Totals> = BTreeMap::new();
+        let mut by_day: BTreeMap<String, Totals> = BTreeMap::new();
+        let mut unpriced = BTreeSet::new();
+        for entry in &entries {
+            let cost = self.entry_cost(entry);
+            if cost.is_none() {
+                unpriced.insert(entry.model.clone());
+            }
+            total.add(entry, cost);
+            by_model
+                .entry((entry.provider.clone(), entry.model.clone()))
+                .or_default()
+                .add(entry, cost);
+            by_chat
+                .entry(entry.chat_id.clone())
+                .or_default()
+                .add(entry, cost);
+            by_day
+                .entry(entry.day.clone())
+                .or_default()
+                .add(entry, cost);
+        }
+
+        let by_model: Vec<Value> = by_model
+            .into_iter()
+            .map(|((provider, model), totals)| {
+                let mut row = totals.to_json();
+                row["priced"] = json!(self.rate(&model).is_some());
+                row["provider"] = json!(provider);
+                row["model"] = json!(model);
+                row
+            })
+            .collect();
+        let by_chat: Vec<Value> = by_chat
+            .into_iter()
+            .map(|(chat_id, totals)| {
+                let mut row = totals.to_json();
+                row["title"] = json!(chat_id.as_ref().and_then(|id| titles.get(id)));
+                row["chat_id"] = json!(chat_id);
+                row
+            })
+            .collect();
+        let by_day: Vec<Value> = by_day
+            .into_iter()
+            .map(|(day, totals)| {
+                let mut row = totals.to_json();
+                row["day"] = json!(day);
+                row
+            })
+            .collect();
+
+        Ok(json!({
+            "currency": "USD",
+            "total": total.to_json(),
+            "by_model": by_model,
+            "by_chat": by_chat,
+            "by_day": by_day,
+            "unpriced_models": unpriced,
+        }))
+    }
+}